@@ -3,7 +3,7 @@
 use jsonpath::{Match, Step};
 
 use json_exporter::config::Config;
-use json_exporter::convert::ResolvedMetric;
+use json_exporter::convert::{MetricFamilies, ResolvedMetric};
 use json_exporter::prepare::PreparedConfig;
 
 use mimalloc::MiMalloc;
@@ -67,22 +67,31 @@ fn bench_elasticsearch(b: &mut Bencher) {
     b.iter(|| {
         buf.clear();
         for endpoint in &prepared_config.endpoints {
+            let mut families = MetricFamilies::default()
+                .with_type_overrides(&prepared_config.metric_type_overrides);
             match endpoint.url.as_str() {
                 "http://example.com:9200/_cluster/health?" => {
                     let es_cluster_health = read_json(ES_CLUSTER_HEALTH);
                     endpoint.process(
-                        &root_metric, &es_cluster_health, &mut buf
+                        &root_metric, &es_cluster_health, &mut families
                     );
+                    families.dump(&mut buf);
                     buf.write_all(b"\n\n").unwrap();
                 }
                 "http://example.com:9200/_nodes/_local/stats?groups=_all" => {
                     let es_nodes_stats = read_json(ES_NODES_STATS);
-                    endpoint.process(&root_metric, &es_nodes_stats, &mut buf);
+                    endpoint.process(
+                        &root_metric, &es_nodes_stats, &mut families
+                    );
+                    families.dump(&mut buf);
                     buf.write_all(b"\n\n").unwrap();
                 }
                 "http://example.com:9200/_all/_stats?groups=_all" => {
                     let es_indices_stats = read_json(ES_INDICES_STATS);
-                    endpoint.process(&root_metric, &es_indices_stats, &mut buf);
+                    endpoint.process(
+                        &root_metric, &es_indices_stats, &mut families
+                    );
+                    families.dump(&mut buf);
                     buf.write_all(b"\n\n").unwrap();
                 }
                 _ => {
@@ -8,6 +8,7 @@ use serde::de::{Visitor, SeqAccess};
 use std::collections::HashMap;
 use std::fmt;
 use std::marker::PhantomData;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use url::Url;
@@ -20,6 +21,19 @@ use crate::prepare::PreparedConfig;
 #[derive(Deserialize)]
 pub struct Config {
     pub namespace: Option<String>,
+    /// Credentials for global labels and all endpoints
+    pub auth: Option<Auth>,
+    /// TLS settings for global labels and all endpoints
+    pub tls: Option<Tls>,
+    /// How to resolve samples with the same name and labels
+    #[serde(default)]
+    pub duplicate_series: DuplicateSeriesPolicy,
+    /// How to handle invalid metric names that are generated from json
+    #[serde(default)]
+    pub invalid_names: InvalidNames,
+    /// Metric names or regular expressions by metric types
+    #[serde(default)]
+    pub metric_type_overrides: HashMap<MetricType, Vec<String>>,
     pub global_labels: Vec<GlobalLabels>,
     pub endpoints: Vec<Endpoint>,
 }
@@ -33,6 +47,15 @@ impl Config {
     ) -> PreparedConfig {
         PreparedConfig::create_from(self, base_url, override_endpoint_urls)?
     }
+
+    /// Checks if requests made with the config send credentials or a client certificate
+    pub fn has_credentials(&self) -> bool {
+        self.auth.is_some() ||
+            self.endpoints.iter().any(|endpoint| endpoint.auth.is_some()) ||
+            self.tls.as_ref().map_or(false, |tls| {
+                tls.cert_file.is_some() || tls.pkcs12_file.is_some()
+            })
+    }
 }
 
 #[derive(Deserialize)]
@@ -45,20 +68,83 @@ pub struct GlobalLabels {
 pub struct Label {
     pub name: String,
     pub value: String,
+    /// Filters are applied to the json value when the label value is a single placeholder
+    #[serde(default)]
+    pub modifiers: Vec<Filter>,
 }
 
 #[derive(Deserialize)]
 pub struct Endpoint {
     pub id: Option<String>,
     pub url: String,
+    /// Overrides global credentials
+    pub auth: Option<Auth>,
     #[serde(default)]
     pub url_parts: UrlParts,
     #[serde(default)]
     pub name: String,
+    /// Endpoint metrics are processed only when the condition is true
+    pub when: Option<Condition>,
     #[serde(deserialize_with = "deserialize_metrics")]
     pub metrics: Vec<Metric>,
 }
 
+#[derive(Deserialize, Default)]
+pub struct Auth {
+    pub basic: Option<BasicAuth>,
+    pub bearer: Option<Secret>,
+    #[serde(default)]
+    pub headers: HashMap<String, Secret>,
+}
+
+#[derive(Deserialize)]
+pub struct BasicAuth {
+    pub username: String,
+    pub password: Option<Secret>,
+}
+
+/// Secret value that can be written inline or read from a file
+/// or an environment variable so it is not stored in the config
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Secret {
+    Value(String),
+    File { file: PathBuf },
+    Env { env: String },
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct Tls {
+    /// CA certificates in PEM format
+    pub ca_file: Option<PathBuf>,
+    /// Client certificate in PEM format, requires `key_file`
+    pub cert_file: Option<PathBuf>,
+    pub key_file: Option<PathBuf>,
+    /// Client certificate and its key in PKCS#12 format
+    pub pkcs12_file: Option<PathBuf>,
+    pub pkcs12_password: Option<Secret>,
+    /// Server certificate is verified against this name instead of the url host
+    pub server_name: Option<String>,
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+}
+
+impl Tls {
+    /// Options that are set in `overrides` take precedence
+    pub fn merge(&self, overrides: &Tls) -> Tls {
+        Tls {
+            ca_file: overrides.ca_file.clone().or_else(|| self.ca_file.clone()),
+            cert_file: overrides.cert_file.clone().or_else(|| self.cert_file.clone()),
+            key_file: overrides.key_file.clone().or_else(|| self.key_file.clone()),
+            pkcs12_file: overrides.pkcs12_file.clone().or_else(|| self.pkcs12_file.clone()),
+            pkcs12_password: overrides.pkcs12_password.clone()
+                .or_else(|| self.pkcs12_password.clone()),
+            server_name: overrides.server_name.clone().or_else(|| self.server_name.clone()),
+            insecure_skip_verify: overrides.insecure_skip_verify || self.insecure_skip_verify,
+        }
+    }
+}
+
 #[derive(Deserialize, Default)]
 pub struct UrlParts {
     #[serde(default)]
@@ -85,10 +171,33 @@ pub struct Metric {
     pub name: Option<String>,
     #[serde(rename = "type", default)]
     pub metric_type: Option<MetricType>,
+    /// Unit of the metric, the metric name must end with it
+    pub unit: Option<String>,
+    /// Description of the metric, can contain placeholders as the name
+    pub help: Option<String>,
     #[serde(default)]
     pub modifiers: Vec<Filter>,
     #[serde(default)]
     pub labels: Vec<Label>,
+    /// Matched json and its nested metrics are skipped when the condition is false
+    pub when: Option<Condition>,
+    /// Path to the object of cumulative counts by upper bounds of a histogram
+    pub buckets: Option<String>,
+    /// Path to the object of values by quantiles or percentiles of a summary
+    pub quantiles: Option<String>,
+    /// Whether the keys of quantiles are percentiles, it is detected by the keys when missing
+    pub percentiles: Option<bool>,
+    /// Path to the sum of observed values of a histogram or a summary
+    pub sum: Option<String>,
+    /// Path to the number of observed values of a histogram or a summary
+    pub count: Option<String>,
+    /// Possible string values of a stateset
+    #[serde(default)]
+    pub states: Vec<String>,
+    /// Label for string values of info and stateset metrics
+    pub value_label: Option<String>,
+    /// Timestamp of the samples, is inherited by nested metrics
+    pub timestamp: Option<Timestamp>,
     #[serde(default)]
     #[serde(deserialize_with = "deserialize_metrics")]
     pub metrics: Vec<Metric>,
@@ -145,27 +254,87 @@ where
     deserializer.deserialize_any(MetricsVisitor(PhantomData))
 }
 
-#[derive(Deserialize, PartialEq, Clone, Copy, Debug)]
+/// Json value found by the path is checked after applying the modifiers,
+/// `null`, `false`, zero and empty values are false
+#[derive(Deserialize)]
+pub struct Condition {
+    pub path: String,
+    #[serde(default)]
+    pub modifiers: Vec<Filter>,
+}
+
+/// Json value found by the path must be seconds since epoch after applying the modifiers
+#[derive(Deserialize)]
+pub struct Timestamp {
+    pub path: String,
+    #[serde(default)]
+    pub modifiers: Vec<Filter>,
+}
+
+#[derive(Deserialize, PartialEq, Eq, Hash, Clone, Copy, Debug)]
 #[serde(rename_all = "lowercase")]
 pub enum MetricType {
     Gauge,
     Counter,
     Untyped,
+    Info,
+    StateSet,
+    Histogram,
+    Summary,
+}
+
+#[derive(Deserialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateSeriesPolicy {
+    /// Keep the first sample
+    First,
+    /// Keep the last sample
+    Last,
+    /// Sum values of all the samples
+    Sum,
+    /// Drop the series reporting an error
+    Error,
+}
+
+impl Default for DuplicateSeriesPolicy {
+    fn default() -> Self {
+        DuplicateSeriesPolicy::First
+    }
+}
+
+#[derive(Deserialize, PartialEq, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidNames {
+    /// Replace invalid characters with underscores
+    Replace,
+    /// Drop the series
+    Drop,
+    /// Convert the name to snake case
+    SnakeCase,
+}
+
+impl Default for InvalidNames {
+    fn default() -> Self {
+        InvalidNames::Replace
+    }
 }
 
 #[derive(Deserialize)]
 pub struct Filter {
     pub name: String,
+    #[serde(default)]
     pub args: serde_json::Value,
 }
 
 
 #[cfg(test)]
 mod tests {
+    use indoc::indoc;
     use serde_yaml;
     use std::fs::File;
     use std::io::BufReader;
-    use crate::config::Config;
+    use std::path::PathBuf;
+    use crate::config::{Auth, Config, Secret, Tls};
 
     #[test]
     fn test_elasticsearch_exporter_config() {
@@ -175,4 +344,45 @@ mod tests {
         );
         let _config: Config = serde_yaml::from_reader(file).unwrap();
     }
+
+    #[test]
+    fn test_auth_config() {
+        let auth: Auth = serde_yaml::from_str(indoc! {"
+            basic:
+              username: exporter
+              password:
+                env: ES_PASSWORD
+            bearer:
+              file: /run/secrets/token
+            headers:
+              Authorization: ApiKey c2VjcmV0
+        "}).unwrap();
+        let basic = auth.basic.unwrap();
+        assert_eq!(basic.username, "exporter");
+        assert!(matches!(basic.password, Some(Secret::Env { env }) if env == "ES_PASSWORD"));
+        assert!(matches!(
+            auth.bearer, Some(Secret::File { file }) if file == PathBuf::from("/run/secrets/token")
+        ));
+        assert!(matches!(
+            auth.headers.get("Authorization"), Some(Secret::Value(v)) if v == "ApiKey c2VjcmV0"
+        ));
+    }
+
+    #[test]
+    fn test_merge_tls() {
+        let tls: Tls = serde_yaml::from_str(indoc! {"
+            ca_file: /etc/ssl/es-ca.pem
+            server_name: es.example.com
+        "}).unwrap();
+        let overrides = Tls {
+            ca_file: Some(PathBuf::from("/etc/ssl/ca.pem")),
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        let merged = tls.merge(&overrides);
+        assert_eq!(merged.ca_file, Some(PathBuf::from("/etc/ssl/ca.pem")));
+        assert_eq!(merged.server_name.as_deref(), Some("es.example.com"));
+        assert_eq!(merged.cert_file, None);
+        assert!(merged.insecure_skip_verify);
+    }
 }
@@ -1,57 +1,518 @@
-use anyhow::{Error as AnyhowError};
+use anyhow::{anyhow, Error as AnyhowError};
 
-use fehler::throws;
+use fehler::{throw, throws};
 
 use jsonpath::{Match, Step};
 
 use serde_json::Value;
 
+use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::fmt::Write;
 use std::io::{Write as IOWrite};
+use std::time::Duration;
 
-use crate::config::MetricType;
-use crate::prepare::{PreparedLabels, PreparedMetric, PreparedMetrics, PreparedEndpoint};
+use crate::config::{DuplicateSeriesPolicy, InvalidNames, MetricType};
+use crate::filters::{is_truthy, BoxedFilter};
+use crate::prepare::{
+    MetricTypeOverrides,
+    JsonSelector,
+    PreparedCondition,
+    PreparedDistribution,
+    PreparedEndpoint,
+    PreparedLabel,
+    PreparedLabels,
+    PreparedMetric,
+    PreparedMetrics,
+    PreparedTimestamp,
+};
 
-type Stack<'a> = Vec<
+type Stack<'a, 'j> = Vec<
     (
         std::slice::Iter<'a, PreparedMetric>,
-        Option<Vec<(&'a Value, ResolvedMetric)>>
+        Option<Vec<(Cow<'j, Value>, ResolvedMetric)>>
     )
 >;
 
+/// Exposition format of the metrics
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Format {
+    Prometheus,
+    OpenMetrics,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::Prometheus
+    }
+}
+
+impl Format {
+    pub const ALL: [Format; 2] = [
+        Format::Prometheus,
+        Format::OpenMetrics,
+    ];
+
+    pub fn content_type(&self) -> &'static str {
+        use Format::*;
+
+        match self {
+            Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+
+    /// Writes the end of the exposition if the format requires it
+    pub fn dump_eof<W: IOWrite>(&self, buf: &mut W) {
+        if *self == Format::OpenMetrics {
+            buf.write(b"# EOF\n").ok();
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum WarningKind {
+    Template,
+    Filter,
+    Dump,
+    Duplicate,
+}
+
+impl WarningKind {
+    pub const ALL: [WarningKind; 4] = [
+        WarningKind::Template,
+        WarningKind::Filter,
+        WarningKind::Dump,
+        WarningKind::Duplicate,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        use WarningKind::*;
+
+        match self {
+            Template => "template",
+            Filter => "filter",
+            Dump => "dump",
+            Duplicate => "duplicate",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Warning {
+    pub kind: WarningKind,
+    pub level: log::Level,
+    pub message: String,
+}
+
+impl Warning {
+    fn warn(kind: WarningKind, message: String) -> Self {
+        Self {
+            kind,
+            level: log::Level::Warn,
+            message,
+        }
+    }
+
+    fn error(kind: WarningKind, message: String) -> Self {
+        Self {
+            kind,
+            level: log::Level::Error,
+            message,
+        }
+    }
+}
+
+/// Samples grouped by metric families in the order the families were first seen.
+/// The text format requires all the samples of a family to be contiguous
+#[derive(Default)]
+pub struct MetricFamilies<'a> {
+    format: Format,
+    duplicate_series: DuplicateSeriesPolicy,
+    invalid_names: InvalidNames,
+    type_overrides: Option<&'a MetricTypeOverrides>,
+    families: Vec<MetricFamily<'a>>,
+    /// Indexes of the families by their names and by the names of their samples
+    family_ixs: HashMap<String, usize>,
+}
+
+#[derive(Default)]
+struct MetricFamily<'a> {
+    metric_type: Option<MetricType>,
+    header: Vec<u8>,
+    samples: Vec<Sample<'a>>,
+    /// Number of the samples that were not dropped
+    series: usize,
+    /// Indexes of the samples by their names with labels
+    sample_ixs: HashMap<Vec<u8>, usize>,
+}
+
+struct Sample<'a> {
+    series: Vec<u8>,
+    /// `None` when the series was dropped because of duplicates
+    value: Option<Value>,
+    timestamp: Option<f64>,
+    config_path: &'a str,
+}
+
+impl<'a> MetricFamilies<'a> {
+    pub fn new(
+        format: Format, duplicate_series: DuplicateSeriesPolicy, invalid_names: InvalidNames
+    ) -> Self {
+        Self {
+            format,
+            duplicate_series,
+            invalid_names,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_type_overrides(mut self, type_overrides: &'a MetricTypeOverrides) -> Self {
+        self.type_overrides = Some(type_overrides);
+        self
+    }
+
+    /// Families are keyed by the names of the format and by the sample names,
+    /// so OpenMetrics counters `docs` and `docs_total` belong to the same family
+    fn family_ix(
+        &mut self, resolved_metric: &ResolvedMetric, metric_type: MetricType, format: Format
+    ) -> usize {
+        let (family_name, sample_name) = resolved_metric.family_and_sample_names(
+            metric_type, format
+        );
+        let family_ix = match self.family_ixs.get(family_name.as_ref()) {
+            Some(&family_ix) => family_ix,
+            None => {
+                self.families.push(MetricFamily::default());
+                self.family_ixs.insert(family_name.into_owned(), self.families.len() - 1);
+                self.families.len() - 1
+            }
+        };
+        self.family_ixs.entry(sample_name.into_owned()).or_insert(family_ix);
+        family_ix
+    }
+
+    /// Number of the samples that will be written
+    pub fn series(&self) -> usize {
+        self.families.iter().map(|family| family.series).sum()
+    }
+
+    /// Resolves the type of the metric and adds its samples to the family
+    fn add_metric(
+        &mut self,
+        metric: &'a PreparedMetric,
+        resolved_metric: &ResolvedMetric,
+        value: &Value,
+        warnings: &mut Vec<Warning>,
+    ) {
+        self.add_samples(
+            resolved_metric, value, metric.distribution.as_ref(), &metric.config_path, warnings
+        );
+    }
+
+    fn add_samples(
+        &mut self,
+        resolved_metric: &ResolvedMetric,
+        value: &Value,
+        distribution: Option<&PreparedDistribution>,
+        config_path: &'a str,
+        warnings: &mut Vec<Warning>,
+    ) {
+        let format = self.format;
+        let duplicate_series = self.duplicate_series;
+        let type_override = self.type_overrides.and_then(|o| o.find(&resolved_metric.name));
+        let family_ix = match self.family_ixs.get(&resolved_metric.name) {
+            Some(&family_ix) => Some(family_ix),
+            None => resolved_metric.resolve_type(value, type_override, None, format)
+                .map(|metric_type| self.family_ix(resolved_metric, metric_type, format)),
+        };
+        let family_and_type = match family_ix {
+            Some(family_ix) => {
+                let family = &mut self.families[family_ix];
+                resolved_metric.resolve_type(value, type_override, family.metric_type, format)
+                    .map(|metric_type| (family, metric_type))
+            }
+            None => None,
+        };
+        let (family, metric_type) = match family_and_type {
+            Some(family_and_type) => family_and_type,
+            None => {
+                // TODO: log metric is not dumped
+                warnings.push(Warning::warn(
+                    WarningKind::Dump,
+                    format!("Error when dumping metric: {:?}", resolved_metric)
+                ));
+                return;
+            }
+        };
+        let samples = match distribution {
+            Some(distribution) => {
+                match distribution.samples(resolved_metric, metric_type, value) {
+                    Ok(samples) => samples,
+                    Err(e) => {
+                        warnings.push(Warning::warn(
+                            WarningKind::Dump,
+                            format!("Error when dumping metric {}: {}", resolved_metric, &e)
+                        ));
+                        return;
+                    }
+                }
+            }
+            None => {
+                let mut sample_series = vec!();
+                resolved_metric.dump_series(metric_type, format, &mut sample_series);
+                vec!((sample_series, value))
+            }
+        };
+        if family.metric_type.is_none() {
+            resolved_metric.dump_header(metric_type, format, &mut family.header);
+            family.metric_type = Some(metric_type);
+        }
+        for (sample_series, sample_value) in samples {
+            if let Some(warning) = family.add_sample(
+                sample_series,
+                sample_value,
+                resolved_metric.timestamp,
+                config_path,
+                duplicate_series,
+            ) {
+                warnings.push(warning);
+            }
+        }
+    }
+
+    pub fn dump<W: IOWrite>(&self, buf: &mut W) {
+        for family in &self.families {
+            // All the samples can be dropped because of duplicates
+            if family.series == 0 {
+                continue;
+            }
+            buf.write(&family.header).ok();
+            for sample in &family.samples {
+                if let Some(value) = &sample.value {
+                    buf.write(&sample.series).ok();
+                    buf.write(b" ").ok();
+                    dump_value(value, buf);
+                    if let Some(timestamp) = sample.timestamp {
+                        dump_timestamp(timestamp, self.format, buf);
+                    }
+                    buf.write(b"\n").ok();
+                }
+            }
+        }
+    }
+}
+
+impl<'a> MetricFamily<'a> {
+    /// Returns a warning when there is already a sample with the same name and labels
+    fn add_sample(
+        &mut self,
+        series: Vec<u8>,
+        value: &Value,
+        timestamp: Option<f64>,
+        config_path: &'a str,
+        duplicate_series: DuplicateSeriesPolicy,
+    ) -> Option<Warning> {
+        use DuplicateSeriesPolicy::*;
+
+        let sample = match self.sample_ixs.get(&series) {
+            Some(&sample_ix) => &mut self.samples[sample_ix],
+            None => {
+                self.sample_ixs.insert(series.clone(), self.samples.len());
+                self.samples.push(Sample {
+                    series,
+                    value: Some(value.clone()),
+                    timestamp,
+                    config_path,
+                });
+                self.series += 1;
+                return None;
+            }
+        };
+
+        let message = format!(
+            "Duplicate series {} at [{}] and [{}]",
+            String::from_utf8_lossy(&series), sample.config_path, config_path
+        );
+        let warning = match duplicate_series {
+            First => {
+                Warning::warn(WarningKind::Duplicate, format!("{}, keeping the first one", message))
+            }
+            Last => {
+                sample.value = Some(value.clone());
+                sample.timestamp = timestamp;
+                sample.config_path = config_path;
+                Warning::warn(WarningKind::Duplicate, format!("{}, keeping the last one", message))
+            }
+            Sum => {
+                match sample.value.as_ref().and_then(|v| sum_values(v, value)) {
+                    Some(sum) => {
+                        sample.value = Some(sum);
+                        Warning::warn(WarningKind::Duplicate, format!("{}, summing them", message))
+                    }
+                    None => Warning::warn(
+                        WarningKind::Duplicate,
+                        format!("{}, cannot sum non-numeric values, keeping the first one", message)
+                    ),
+                }
+            }
+            Error => {
+                if sample.value.take().is_some() {
+                    self.series -= 1;
+                }
+                Warning::error(WarningKind::Duplicate, format!("{}, dropping the series", message))
+            }
+        };
+        Some(warning)
+    }
+}
+
+fn sum_values(a: &Value, b: &Value) -> Option<Value> {
+    fn as_number(v: &Value) -> Option<Value> {
+        match v {
+            Value::Number(_) => Some(v.clone()),
+            Value::Bool(v) => Some(Value::from(*v as u8)),
+            _ => None,
+        }
+    }
+
+    let (a, b) = (as_number(a)?, as_number(b)?);
+    match (a.as_i64(), b.as_i64()) {
+        (Some(a), Some(b)) if a.checked_add(b).is_some() => Some(Value::from(a + b)),
+        _ => Some(Value::from(a.as_f64()? + b.as_f64()?)),
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ProcessStats {
+    /// Number of written samples
+    pub series: usize,
+    pub warnings: Vec<Warning>,
+}
+
 impl PreparedEndpoint {
-    pub fn process<W: IOWrite>(
-        &self,
+    pub fn process<'a>(
+        &'a self,
         root_metric: &ResolvedMetric,
         json: &Value,
-        buf: &mut W
-    ) -> Vec<(log::Level, String)> {
+        families: &mut MetricFamilies<'a>,
+    ) -> ProcessStats {
+        if let Some(condition) = &self.condition {
+            match condition.check(json) {
+                Ok(true) => {}
+                Ok(false) => return ProcessStats::default(),
+                Err(e) => {
+                    return ProcessStats {
+                        series: 0,
+                        warnings: vec!(Warning::warn(
+                            WarningKind::Filter,
+                            format!("Error when checking endpoint condition: {}", &e)
+                        )),
+                    };
+                }
+            }
+        }
         let endpoint_metric = self.resolve_metric().merge_with_parent(root_metric);
-        self.metrics.process(&endpoint_metric, json, buf)
+        self.metrics.process(&endpoint_metric, json, families)
     }
 
     fn resolve_metric(&self) -> ResolvedMetric {
         ResolvedMetric {
             name: self.name.clone(),
-            metric_type: None,
-            labels: BTreeMap::new(),
+            ..Default::default()
+        }
+    }
+
+    /// Value of the `endpoint` label for synthetic endpoint metrics
+    pub fn label_value(&self) -> &str {
+        if let Some(id) = &self.id {
+            id
+        } else if !self.name.is_empty() {
+            &self.name
+        } else {
+            self.url.path()
+        }
+    }
+
+    fn resolve_status_metric(
+        &self, name: &str, unit: Option<&str>, help: &str
+    ) -> ResolvedMetric {
+        let mut labels = BTreeMap::new();
+        labels.insert(
+            "endpoint".to_string(), escape_label_value(self.label_value().to_string())
+        );
+        ResolvedMetric {
+            name: name.to_string(),
+            metric_type: Some(MetricType::Gauge),
+            unit: unit.map(str::to_string),
+            help: Some(help.to_string()),
+            timestamp: None,
+            labels,
         }
     }
 }
 
+/// Result of scraping a single endpoint
+pub struct EndpointStatus<'a> {
+    pub endpoint: &'a PreparedEndpoint,
+    pub up: bool,
+    pub scrape_duration: Duration,
+}
+
+/// Config path of the endpoint status series in the duplicate series warnings
+const ENDPOINTS_STATUS_PATH: &str = "endpoints status";
+
+/// Adds `endpoint_up` and `endpoint_scrape_duration_seconds` metrics
+/// so failed endpoints are visible instead of just missing. They are added
+/// to the families of the config metrics, so a config metric with the same name
+/// shares the family and its duplicate series are resolved by the policy
+pub fn add_endpoints_status(
+    root_metric: &ResolvedMetric,
+    statuses: &[EndpointStatus],
+    families: &mut MetricFamilies,
+) -> Vec<Warning> {
+    let mut warnings = vec!();
+    for status in statuses {
+        let metric = status.endpoint
+            .resolve_status_metric("endpoint_up", None, "Whether the endpoint was scraped successfully")
+            .merge_with_parent(root_metric);
+        families.add_samples(
+            &metric, &Value::from(status.up), None, ENDPOINTS_STATUS_PATH, &mut warnings
+        );
+    }
+    for status in statuses {
+        let metric = status.endpoint
+            .resolve_status_metric(
+                "endpoint_scrape_duration_seconds",
+                Some("seconds"),
+                "Time spent to fetch and process the endpoint",
+            )
+            .merge_with_parent(root_metric);
+        families.add_samples(
+            &metric,
+            &Value::from(status.scrape_duration.as_secs_f64()),
+            None,
+            ENDPOINTS_STATUS_PATH,
+            &mut warnings,
+        );
+    }
+    warnings
+}
+
 impl PreparedMetrics {
     // TODO: refactor this api
-    pub fn process<W: IOWrite>(
-        &self,
+    pub fn process<'a>(
+        &'a self,
         root_metric: &ResolvedMetric,
         json: &Value,
-        buf: &mut W
-    ) -> Vec<(log::Level, String)> {
+        families: &mut MetricFamilies<'a>,
+    ) -> ProcessStats {
+        let invalid_names = families.invalid_names;
         let mut stack: Stack = vec!();
         stack.push((self.iter(), None));
-        let mut seen_metrics = HashMap::new();
+        let series_before = families.series();
         let mut warnings = vec!();
 
         // println!("{:?}", json);
@@ -63,7 +524,24 @@ impl PreparedMetrics {
                     let mut state = vec!();
                     if let Some(parent_state) = parent_state {
                         for (parent_json, parent_metric) in parent_state.iter() {
-                            metric.resolve_into(parent_metric, parent_json, &mut state, &mut warnings);
+                            match parent_json {
+                                Cow::Borrowed(parent_json) => {
+                                    metric.resolve_into(
+                                        parent_metric, parent_json, &mut state, &mut warnings
+                                    );
+                                }
+                                Cow::Owned(parent_json) => {
+                                    // Parent json was modified so we cannot borrow from it
+                                    let mut owned_state = vec!();
+                                    metric.resolve_into(
+                                        parent_metric, parent_json, &mut owned_state, &mut warnings
+                                    );
+                                    state.extend(
+                                        owned_state.into_iter()
+                                            .map(|(json, m)| (Cow::Owned(json.into_owned()), m))
+                                    );
+                                }
+                            }
                         }
                     } else {
                         metric.resolve_into(root_metric, json, &mut state, &mut warnings);
@@ -77,44 +555,49 @@ impl PreparedMetrics {
                         //     println!("  {:?}", json);
                         // }
 
-                        'metrics_loop: for (json, resolved_metric) in &state {
-                            // TODO: apply filters for all values not only leaf
-                            let mut _value;
-                            let value = if metric.filters.is_empty() {
-                                json
-                            } else {
-                                _value = (*json).clone();
-                                for filter in &metric.filters {
-                                    match filter.apply(&_value) {
-                                        Ok(v) => {
-                                            _value = v;
-                                        }
-                                        Err(e) => {
-                                            // TODO: log error
-                                            warnings.push((
-                                                log::Level::Warn,
-                                                format!("Error when applying filter: {}", &e)
-                                            ));
-                                            continue 'metrics_loop;
-                                        },
-                                    }
+                        for (json, resolved_metric) in &state {
+                            let value = match apply_filters(&metric.filters, json) {
+                                Ok(value) => value,
+                                Err(e) => {
+                                    // TODO: log error
+                                    warnings.push(Warning::warn(
+                                        WarningKind::Filter,
+                                        format!("Error when applying filter: {}", &e)
+                                    ));
+                                    continue;
                                 }
-                                &_value
                             };
-                            let metric_type = seen_metrics.get(&resolved_metric.name).cloned();
-                            let dumped_metric_type = resolved_metric.dump(value, metric_type, buf);
-                            if let Some(dumped_metric_type) = dumped_metric_type {
-                                if metric_type.is_none() {
-                                    seen_metrics.insert(
-                                        resolved_metric.name.clone(), dumped_metric_type
-                                    );
+                            let value: &Value = &value;
+                            let resolved_metric = match sanitize_metric_name(
+                                &resolved_metric.name, invalid_names
+                            ) {
+                                Some(Cow::Borrowed(_)) => Cow::Borrowed(resolved_metric),
+                                Some(Cow::Owned(name)) => Cow::Owned(ResolvedMetric {
+                                    name,
+                                    ..resolved_metric.clone()
+                                }),
+                                None => {
+                                    warnings.push(Warning::warn(
+                                        WarningKind::Dump,
+                                        format!("Invalid metric name: {:?}", &resolved_metric.name)
+                                    ));
+                                    continue;
+                                }
+                            };
+                            let values = match metric.labeled_values(&resolved_metric, value) {
+                                Ok(values) => values,
+                                Err(e) => {
+                                    warnings.push(Warning::warn(
+                                        WarningKind::Dump,
+                                        format!("Error when dumping metric {}: {}", resolved_metric, &e)
+                                    ));
+                                    continue;
                                 }
-                            } else {
-                                // TODO: log metric is not dumped
-                                warnings.push((
-                                    log::Level::Warn,
-                                    format!("Error when dumping metric: {:?}", resolved_metric)
-                                ));
+                            };
+                            for (resolved_metric, value) in values {
+                                families.add_metric(
+                                    metric, &resolved_metric, &value, &mut warnings
+                                );
                             }
                         }
                     } else {
@@ -125,6 +608,9 @@ impl PreparedMetrics {
                         //     println!("  {:?}", json);
                         // }
 
+                        if !metric.filters.is_empty() {
+                            state = metric.filter_subtrees(state, &mut warnings);
+                        }
                         stack.push((metric.metrics.iter(), Some(state)));
                     }
                 }
@@ -134,7 +620,29 @@ impl PreparedMetrics {
             }
         }
 
-        warnings
+        ProcessStats {
+            // Duplicates of the previous endpoints can be dropped
+            series: families.series().saturating_sub(series_before),
+            warnings,
+        }
+    }
+}
+
+impl MetricType {
+    fn type_name(&self, format: Format) -> &'static str {
+        use MetricType::*;
+
+        match (self, format) {
+            (Gauge, _) => "gauge",
+            (Counter, _) => "counter",
+            (Untyped, Format::Prometheus) => "untyped",
+            (Untyped, Format::OpenMetrics) => "unknown",
+            (Info, Format::Prometheus) | (StateSet, Format::Prometheus) => "gauge",
+            (Info, Format::OpenMetrics) => "info",
+            (StateSet, Format::OpenMetrics) => "stateset",
+            (Histogram, _) => "histogram",
+            (Summary, _) => "summary",
+        }
     }
 }
 
@@ -143,41 +651,35 @@ impl PreparedLabels {
     pub fn resolve(&self, found: &Match) -> BTreeMap<String, String> {
         let mut labels = BTreeMap::new();
         for label in &self.labels {
-            let label_value = label.value_processor.apply(found)?;
+            let label_value = label.resolve(found)?;
             // Escape label values here so we shouldn't escape them every time
             // when dumping
-            let safe_value = match self.should_escape_label_value(&label_value) {
-                0 => label_value,
-                num_escapes => self.escape_label_value(&label_value, num_escapes),
-            };
             labels.insert(
-                label.name.clone(), safe_value
+                label.name.clone(), escape_label_value(label_value)
             );
         }
         labels
     }
 
-    fn should_escape_label_value(&self, label_value: &str) -> usize {
-        let mut count = 0;
-        for c in label_value.chars() {
-            if c == '\\' || c == '"' || c == '\n' {
-                count += 1;
-            }
-        }
-        count
-    }
+}
 
-    fn escape_label_value(&self, label_value: &str, num_escapes: usize) -> String {
-        let mut escaped_value = String::with_capacity(label_value.len() + num_escapes * 2);
-        for c in label_value.chars() {
-            match c {
-                '"' => escaped_value.push_str("\\\""),
-                '\n' => escaped_value.push_str("\\n"),
-                '\\' => escaped_value.push_str("\\\\"),
-                c => escaped_value.push(c),
-            }
+impl PreparedLabel {
+    #[throws(AnyhowError)]
+    fn resolve(&self, found: &Match) -> String {
+        if self.filters.is_empty() {
+            return self.value_processor.apply(found)?;
+        }
+        let mut value = self.value_processor.apply_value(found)?;
+        for filter in &self.filters {
+            value = filter.apply(&value)?;
+        }
+        match value {
+            Value::String(v) => v,
+            Value::Bool(v) => v.to_string(),
+            Value::Number(v) => v.to_string(),
+            Value::Null => String::new(),
+            _ => throw!(anyhow!("Value of the label {} must be a scalar", &self.name)),
         }
-        escaped_value
     }
 }
 
@@ -208,9 +710,22 @@ impl PreparedMetric {
             }
         };
 
+        let help = match &self.help_processor {
+            Some(help_processor) => Some(help_processor.apply(found)?),
+            None => None,
+        };
+
+        let timestamp = match &self.timestamp {
+            Some(timestamp) => timestamp.resolve(found.value)?,
+            None => None,
+        };
+
         ResolvedMetric {
             name,
             metric_type: self.metric_type,
+            unit: self.unit.clone(),
+            help,
+            timestamp,
             labels: self.labels.resolve(found)?,
         }
     }
@@ -219,114 +734,615 @@ impl PreparedMetric {
         &'a self,
         parent: &'b ResolvedMetric,
         json: &'a Value,
-        resolved_metrics: &'b mut Vec<(&'a Value, ResolvedMetric)>,
-        warnings: &mut Vec<(log::Level, String)>,
+        resolved_metrics: &'b mut Vec<(Cow<'a, Value>, ResolvedMetric)>,
+        warnings: &mut Vec<Warning>,
     ) {
         for found in self.selector.find(json) {
+            if let Some(condition) = &self.condition {
+                match condition.check(found.value) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        warnings.push(Warning::warn(
+                            WarningKind::Filter,
+                            format!("Error when checking condition: {}", &e)
+                        ));
+                        continue;
+                    }
+                }
+            }
             let resolved_metric = match self.resolve(&found) {
                 Ok(m) => m,
                 Err(e) => {
                     warnings.push(
-                        (log::Level::Warn, format!("{}", e))
+                        Warning::warn(WarningKind::Template, format!("{}", e))
                     );
                     continue;
                 }
             };
             resolved_metrics.push((
-                found.value,
+                Cow::Borrowed(found.value),
                 resolved_metric.merge_with_parent(parent)
             ));
         }
     }
-}
 
-#[derive(Clone, Default, Debug)]
-pub struct ResolvedMetric {
-    pub name: String,
-    pub metric_type: Option<MetricType>,
-    // Use BTreeMap for reproducible tests
-    pub labels: BTreeMap<String, String>,
-}
+    /// String values of info and stateset metrics are moved into labels:
+    /// an info sample is always 1 and every configured state gets its own sample
+    #[throws(AnyhowError)]
+    fn labeled_values<'m>(
+        &self, resolved_metric: &'m ResolvedMetric, value: &'m Value
+    ) -> Vec<(Cow<'m, ResolvedMetric>, Cow<'m, Value>)> {
+        let state = match (resolved_metric.metric_type, value) {
+            (Some(MetricType::Info), Value::String(state)) |
+            (Some(MetricType::StateSet), Value::String(state)) => state,
+            _ => return vec!((Cow::Borrowed(resolved_metric), Cow::Borrowed(value))),
+        };
+        let with_label = |label_name: &str, label_value: &str, metric_type, value: Value| {
+            let mut labeled_metric = resolved_metric.clone();
+            labeled_metric.metric_type = Some(metric_type);
+            labeled_metric.labels.insert(
+                label_name.to_string(), escape_label_value(label_value.to_string())
+            );
+            (Cow::Owned(labeled_metric), Cow::Owned(value))
+        };
 
-impl ResolvedMetric {
-    pub fn new_root(name: String, labels: BTreeMap<String, String>) -> Self {
-        Self {
-            metric_type: None,
-            name,
-            labels,
+        if resolved_metric.metric_type == Some(MetricType::Info) {
+            let label_name = self.value_label.as_deref().unwrap_or("value");
+            return vec!(with_label(label_name, state, MetricType::Info, Value::from(1)));
         }
-    }
-
-    fn merge_with_parent(mut self, parent: &ResolvedMetric) -> Self {
-        self.name = if parent.name.is_empty() {
-            self.name.clone()
-        } else if self.name.is_empty() {
-            parent.name.clone()
+        // OpenMetrics requires the label of a stateset to be named after the metric,
+        // so a stateset with another label is written as a gauge in both formats
+        let label_name = self.value_label.as_deref().unwrap_or("state");
+        let metric_type = if label_name == resolved_metric.name {
+            MetricType::StateSet
         } else {
-            format!("{}_{}", &parent.name, &self.name)
+            MetricType::Gauge
         };
-        for (parent_label_name, parent_label_value) in parent.labels.iter() {
-            self.labels.entry(parent_label_name.clone())
-                .or_insert_with(|| parent_label_value.clone());
+        if self.states.is_empty() {
+            return vec!(with_label(label_name, state, metric_type, Value::from(true)));
         }
-        self
+        if !self.states.contains(state) {
+            throw!(anyhow!("Unknown state: {:?}", state));
+        }
+        self.states.iter()
+            .map(|s| with_label(label_name, s, metric_type, Value::from(s == state)))
+            .collect()
     }
 
-    fn dump<W: IOWrite>(
+    /// Modifiers of a parent metric transform json before resolving child metrics.
+    /// When the modifiers result in `false` or `null` the whole subtree is skipped
+    /// and `true` keeps the original json
+    fn filter_subtrees<'a>(
         &self,
-        value: &Value,
-        seen_metric_type: Option<MetricType>,
-        buf: &mut W,
-    ) -> Option<MetricType> {
-        // See: https://prometheus.io/docs/instrumenting/exposition_formats/#comments-help-text-and-type-information
-
-        use MetricType::*;
-
-        let metric_type = match (self.metric_type, seen_metric_type) {
-            (Some(mtype), None) | (None, Some(mtype)) => mtype,
-            (Some(mtype), Some(seen)) => {
-                if mtype != seen {
-                    return None;
+        state: Vec<(Cow<'a, Value>, ResolvedMetric)>,
+        warnings: &mut Vec<Warning>,
+    ) -> Vec<(Cow<'a, Value>, ResolvedMetric)> {
+        let mut filtered_state = Vec::with_capacity(state.len());
+        for (json, resolved_metric) in state {
+            let filtered_json = match apply_filters(&self.filters, &json) {
+                Ok(Cow::Owned(filtered_json)) => filtered_json,
+                Ok(Cow::Borrowed(_)) => unreachable!(),
+                Err(e) => {
+                    warnings.push(Warning::warn(
+                        WarningKind::Filter,
+                        format!("Error when applying filter: {}", &e)
+                    ));
+                    continue;
                 }
-                seen
+            };
+            match filtered_json {
+                Value::Bool(false) | Value::Null => {}
+                Value::Bool(true) => filtered_state.push((json, resolved_metric)),
+                filtered_json => filtered_state.push((Cow::Owned(filtered_json), resolved_metric)),
             }
-            (None, None) => {
-                match value {
-                    Value::String(_) => Untyped,
-                    Value::Number(_) | Value::Bool(_) => Gauge,
-                    _ => return None,
-                }
+        }
+        filtered_state
+    }
+}
+
+impl MetricTypeOverrides {
+    /// Names are matched with and without the namespace
+    fn find(&self, name: &str) -> Option<MetricType> {
+        let short_name = self.namespace_prefix.as_ref()
+            .and_then(|prefix| name.strip_prefix(prefix.as_str()));
+        let names = std::iter::once(name).chain(short_name);
+        for name in names.clone() {
+            if let Some(&metric_type) = self.names.get(name) {
+                return Some(metric_type);
             }
-        };
+        }
+        for (regex, metric_type) in &self.patterns {
+            if names.clone().any(|name| regex.is_match(name)) {
+                return Some(*metric_type);
+            }
+        }
+        None
+    }
+}
 
-        if !self.check_value(value, metric_type) {
-            return None;
+impl PreparedDistribution {
+    /// Returns `_bucket`, `_sum` and `_count` samples of a histogram
+    /// or quantile, `_sum` and `_count` samples of a summary
+    #[throws(AnyhowError)]
+    fn samples<'v>(
+        &'v self, metric: &ResolvedMetric, metric_type: MetricType, json: &'v Value
+    ) -> Vec<(Vec<u8>, &'v Value)> {
+        let find = |selector: &'v JsonSelector| -> Option<&'v Value> {
+            selector.find(json).next()
+                .map(|found| found.value)
+                .filter(|v| !v.is_null())
+        };
+        let sum = self.sum.as_ref().and_then(find);
+        let count = self.count.as_ref().and_then(find);
+        let points = match find(&self.points) {
+            Some(Value::Object(points)) => points,
+            Some(_) => throw!(anyhow!("Expected an object at {}", &self.points.expression)),
+            None => throw!(anyhow!("Nothing found at {}", &self.points.expression)),
+        };
+        let mut parsed_points = Vec::with_capacity(points.len() + 1);
+        for (key, value) in points {
+            let bound = match parse_bound(key) {
+                Some(bound) => bound,
+                None => throw!(anyhow!("Cannot parse bound: {:?}", key)),
+            };
+            if !value.is_number() {
+                throw!(anyhow!("Value of {:?} must be a number", key));
+            }
+            parsed_points.push((bound, value));
         }
 
-        if seen_metric_type.is_none() {
-            buf.write(b"# TYPE ").ok();
-            buf.write(self.name.as_bytes()).ok();
-            match metric_type {
-                Gauge => {
-                    buf.write(b" gauge\n").ok();
+        let mut samples = vec!();
+        match metric_type {
+            MetricType::Histogram => {
+                parsed_points.sort_by(|(b1, _), (b2, _)| b1.partial_cmp(b2).unwrap_or(Ordering::Equal));
+                if !matches!(parsed_points.last(), Some((b, _)) if b.is_infinite()) {
+                    match count {
+                        Some(count) => parsed_points.push((f64::INFINITY, count)),
+                        None => throw!(anyhow!("Histogram must have +Inf bucket or count")),
+                    }
+                }
+                let inf_count = parsed_points.last().map(|(_, v)| *v);
+                for (bound, value) in parsed_points {
+                    samples.push((
+                        metric.distribution_series("_bucket", Some(("le", format_bound(bound)))),
+                        value
+                    ));
+                }
+                if let Some(sum) = sum {
+                    samples.push((metric.distribution_series("_sum", None), sum));
                 }
-                Counter => {
-                    buf.write(b" counter\n").ok();
+                if let Some(count) = count.or(inf_count) {
+                    samples.push((metric.distribution_series("_count", None), count));
                 }
-                Untyped => {
-                    buf.write(b" untyped\n").ok();
+            }
+            MetricType::Summary => {
+                // Keys like 99.9 or p95 are percentiles, then 1.0 is a percentile too
+                let percentiles = self.percentiles.unwrap_or_else(|| {
+                    parsed_points.iter().any(|(bound, _)| *bound > 1.0)
+                });
+                if percentiles {
+                    // Points are parsed in the order of the keys
+                    for ((bound, _), key) in parsed_points.iter_mut().zip(points.keys()) {
+                        *bound = match parse_percentile(key) {
+                            Some(quantile) => quantile,
+                            None => throw!(anyhow!("Cannot parse percentile: {:?}", key)),
+                        };
+                    }
+                }
+                for (bound, value) in parsed_points.iter() {
+                    if !(0.0..=1.0).contains(bound) {
+                        throw!(anyhow!("Invalid quantile {} with value {}", bound, value));
+                    }
+                }
+                parsed_points.sort_by(|(b1, _), (b2, _)| b1.partial_cmp(b2).unwrap_or(Ordering::Equal));
+                for (bound, value) in parsed_points {
+                    samples.push((
+                        metric.distribution_series("", Some(("quantile", format_bound(bound)))),
+                        value
+                    ));
+                }
+                if let Some(sum) = sum {
+                    samples.push((metric.distribution_series("_sum", None), sum));
+                }
+                if let Some(count) = count {
+                    samples.push((metric.distribution_series("_count", None), count));
                 }
             }
+            _ => throw!(anyhow!("Metric of type {:?} cannot have buckets or quantiles", metric_type)),
+        }
+        samples
+    }
+}
+
+/// Extracts a number from keys like `0.5`, `+Inf`, `p99` or `95thPercentile`
+fn parse_bound(key: &str) -> Option<f64> {
+    let key = key.trim();
+    if key.trim_start_matches('+').eq_ignore_ascii_case("inf") ||
+        key.trim_start_matches('+').eq_ignore_ascii_case("infinity")
+    {
+        return Some(f64::INFINITY);
+    }
+    bound_number(key)?.parse().ok()
+}
+
+fn bound_number(key: &str) -> Option<&str> {
+    let start = key.find(|c: char| c.is_ascii_digit())?;
+    let start = if key[..start].ends_with('-') { start - 1 } else { start };
+    let number = &key[start..];
+    let end = number.char_indices()
+        .skip(1)
+        .find(|(_, c)| !c.is_ascii_digit() && *c != '.')
+        .map(|(ix, _)| ix)
+        .unwrap_or(number.len());
+    Some(&number[..end])
+}
+
+/// Converts a percentile key to a quantile by moving the decimal point of the number,
+/// so `99.9` is exactly `0.999`. Integers above 100 like in `p999` or `9999thPercentile`
+/// are the digits after the point: `0.999` and `0.9999`
+fn parse_percentile(key: &str) -> Option<f64> {
+    let number = bound_number(key.trim())?;
+    if number.starts_with('-') {
+        return None;
+    }
+    let (int_part, frac_part) = match number.find('.') {
+        Some(ix) => (&number[..ix], &number[ix + 1..]),
+        None => (number, ""),
+    };
+    let int_part = int_part.trim_start_matches('0');
+    let quantile = if frac_part.is_empty() && int_part.len() > 2 && int_part != "100" {
+        format!("0.{}", int_part)
+    } else {
+        let int_part = format!("{:0>3}", int_part);
+        let (ones, hundredths) = int_part.split_at(int_part.len() - 2);
+        format!("{}.{}{}", ones, hundredths, frac_part)
+    };
+    quantile.parse().ok()
+}
+
+/// Bounds are written as floats so the same bound always has the same label value
+fn format_bound(bound: f64) -> String {
+    if bound.is_infinite() {
+        if bound > 0.0 { "+Inf".to_string() } else { "-Inf".to_string() }
+    } else {
+        format!("{:?}", bound)
+    }
+}
+
+impl PreparedTimestamp {
+    /// Returns seconds since epoch, `None` if the timestamp is missing
+    #[throws(AnyhowError)]
+    fn resolve(&self, json: &Value) -> Option<f64> {
+        let null = Value::Null;
+        let value = self.selector.find(json).next()
+            .map(|found| found.value)
+            .unwrap_or(&null);
+        let value = apply_filters(&self.filters, value)?;
+        match value.as_ref() {
+            Value::Null => None,
+            Value::Number(v) => v.as_f64(),
+            v => throw!(anyhow!("Timestamp must be a number: {}", v)),
+        }
+    }
+}
+
+impl PreparedCondition {
+    #[throws(AnyhowError)]
+    fn check(&self, json: &Value) -> bool {
+        let null = Value::Null;
+        let value = self.selector.find(json).next()
+            .map(|found| found.value)
+            .unwrap_or(&null);
+        is_truthy(&apply_filters(&self.filters, value)?)
+    }
+}
+
+#[throws(AnyhowError)]
+fn apply_filters<'a>(filters: &[BoxedFilter], json: &'a Value) -> Cow<'a, Value> {
+    let mut filtered_value = None;
+    for filter in filters {
+        filtered_value = Some(filter.apply(filtered_value.as_ref().unwrap_or(json))?);
+    }
+    match filtered_value {
+        Some(value) => Cow::Owned(value),
+        None => Cow::Borrowed(json),
+    }
+}
+
+pub(crate) fn is_metric_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == ':'
+}
+
+pub(crate) fn is_valid_metric_name(name: &str) -> bool {
+    !name.is_empty() &&
+        !name.starts_with(|c: char| c.is_ascii_digit()) &&
+        name.chars().all(is_metric_name_char)
+}
+
+pub(crate) fn is_valid_label_name(name: &str) -> bool {
+    !name.is_empty() &&
+        !name.starts_with(|c: char| c.is_ascii_digit()) &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Returns `None` if the series with such a name must be dropped
+fn sanitize_metric_name(name: &str, invalid_names: InvalidNames) -> Option<Cow<'_, str>> {
+    if is_valid_metric_name(name) {
+        return Some(Cow::Borrowed(name));
+    }
+    if invalid_names == InvalidNames::Drop {
+        return None;
+    }
+
+    let mut sanitized_name = String::with_capacity(name.len() + 1);
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        sanitized_name.push('_');
+    }
+    match invalid_names {
+        InvalidNames::Replace => {
+            for c in name.chars() {
+                sanitized_name.push(if is_metric_name_char(c) { c } else { '_' });
+            }
+        }
+        InvalidNames::SnakeCase => {
+            let mut prev_char = None;
+            for c in name.chars() {
+                if c.is_ascii_uppercase() {
+                    if matches!(prev_char, Some(p) if p.is_ascii_lowercase() || p.is_ascii_digit()) {
+                        sanitized_name.push('_');
+                    }
+                    sanitized_name.push(c.to_ascii_lowercase());
+                } else if is_metric_name_char(c) {
+                    sanitized_name.push(c);
+                } else if !sanitized_name.ends_with('_') {
+                    sanitized_name.push('_');
+                }
+                prev_char = Some(c);
+            }
+        }
+        InvalidNames::Drop => unreachable!(),
+    }
+    if sanitized_name.is_empty() {
+        return None;
+    }
+    Some(Cow::Owned(sanitized_name))
+}
+
+pub(crate) fn escape_label_value(label_value: String) -> String {
+    match should_escape_label_value(&label_value) {
+        0 => label_value,
+        num_escapes => escape_label_value_with(&label_value, num_escapes),
+    }
+}
+
+fn should_escape_label_value(label_value: &str) -> usize {
+    let mut count = 0;
+    for c in label_value.chars() {
+        if c == '\\' || c == '"' || c == '\n' {
+            count += 1;
+        }
+    }
+    count
+}
+
+fn escape_label_value_with(label_value: &str, num_escapes: usize) -> String {
+    let mut escaped_value = String::with_capacity(label_value.len() + num_escapes * 2);
+    for c in label_value.chars() {
+        match c {
+            '"' => escaped_value.push_str("\\\""),
+            '\n' => escaped_value.push_str("\\n"),
+            '\\' => escaped_value.push_str("\\\\"),
+            c => escaped_value.push(c),
+        }
+    }
+    escaped_value
+}
+
+/// Prometheus text format escapes only backslashes and line feeds in the help text
+/// while OpenMetrics escapes double quotes too
+fn escape_help(help: &str, format: Format) -> Cow<'_, str> {
+    let should_escape = |c: char| {
+        c == '\\' || c == '\n' || (c == '"' && format == Format::OpenMetrics)
+    };
+    if !help.contains(should_escape) {
+        return Cow::Borrowed(help);
+    }
+    let mut escaped_help = String::with_capacity(help.len() + 8);
+    for c in help.chars() {
+        match c {
+            '\\' => escaped_help.push_str("\\\\"),
+            '\n' => escaped_help.push_str("\\n"),
+            '"' if format == Format::OpenMetrics => escaped_help.push_str("\\\""),
+            c => escaped_help.push(c),
+        }
+    }
+    Cow::Owned(escaped_help)
+}
+
+#[derive(Clone, Default, Debug)]
+pub struct ResolvedMetric {
+    pub name: String,
+    pub metric_type: Option<MetricType>,
+    pub unit: Option<String>,
+    pub help: Option<String>,
+    /// Seconds since epoch
+    pub timestamp: Option<f64>,
+    // Use BTreeMap for reproducible tests
+    pub labels: BTreeMap<String, String>,
+}
+
+impl ResolvedMetric {
+    pub fn new_root(name: String, labels: BTreeMap<String, String>) -> Self {
+        Self {
+            name,
+            labels,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn merge_with_parent(mut self, parent: &ResolvedMetric) -> Self {
+        self.name = if parent.name.is_empty() {
+            self.name.clone()
+        } else if self.name.is_empty() {
+            parent.name.clone()
+        } else {
+            format!("{}_{}", &parent.name, &self.name)
+        };
+        if self.help.is_none() {
+            self.help = parent.help.clone();
+        }
+        if self.timestamp.is_none() {
+            self.timestamp = parent.timestamp;
+        }
+        for (parent_label_name, parent_label_value) in parent.labels.iter() {
+            self.labels.entry(parent_label_name.clone())
+                .or_insert_with(|| parent_label_value.clone());
+        }
+        self
+    }
+
+    pub(crate) fn dump<W: IOWrite>(
+        &self,
+        value: &Value,
+        seen_metric_type: Option<MetricType>,
+        format: Format,
+        buf: &mut W,
+    ) -> Option<MetricType> {
+        // See: https://prometheus.io/docs/instrumenting/exposition_formats/#comments-help-text-and-type-information
+        // and: https://github.com/OpenObservability/OpenMetrics/blob/main/specification/OpenMetrics.md
+
+        let metric_type = self.resolve_type(value, None, seen_metric_type, format)?;
+        if seen_metric_type.is_none() {
+            self.dump_header(metric_type, format, buf);
+        }
+        self.dump_series(metric_type, format, buf);
+        buf.write(b" ").ok();
+        dump_value(value, buf);
+        if let Some(timestamp) = self.timestamp {
+            dump_timestamp(timestamp, format, buf);
         }
-        self.dump_metric(buf);
-        buf.write(b" ").ok();
-        self.dump_value(value, buf);
         buf.write(b"\n").ok();
         Some(metric_type)
     }
 
+    /// Checks the value is compatible with the type of the metric
+    /// and the type that was seen before for the same family
+    fn resolve_type(
+        &self,
+        value: &Value,
+        type_override: Option<MetricType>,
+        seen_metric_type: Option<MetricType>,
+        format: Format,
+    ) -> Option<MetricType> {
+        use MetricType::*;
+
+        let metric_type = match (type_override.or(self.metric_type), seen_metric_type) {
+            (Some(mtype), None) | (None, Some(mtype)) => mtype,
+            (Some(mtype), Some(seen)) => {
+                if mtype != seen {
+                    return None;
+                }
+                seen
+            }
+            (None, None) => {
+                match value {
+                    Value::String(_) => Untyped,
+                    Value::Number(_) | Value::Bool(_) => Gauge,
+                    _ => return None,
+                }
+            }
+        };
+
+        if !self.check_value(value, metric_type, format) {
+            return None;
+        }
+
+        let (family_name, _) = self.family_and_sample_names(metric_type, format);
+        if metric_type == StateSet && format == Format::OpenMetrics &&
+            !self.labels.contains_key(family_name.as_ref())
+        {
+            return None;
+        }
+
+        Some(metric_type)
+    }
+
+    fn dump_header<W: IOWrite>(&self, metric_type: MetricType, format: Format, buf: &mut W) {
+        let (family_name, _) = self.family_and_sample_names(metric_type, format);
+        if let Some(help) = &self.help {
+            buf.write(b"# HELP ").ok();
+            buf.write(family_name.as_bytes()).ok();
+            buf.write(b" ").ok();
+            buf.write(escape_help(help, format).as_bytes()).ok();
+            buf.write(b"\n").ok();
+        }
+        buf.write(b"# TYPE ").ok();
+        buf.write(family_name.as_bytes()).ok();
+        buf.write(b" ").ok();
+        buf.write(metric_type.type_name(format).as_bytes()).ok();
+        buf.write(b"\n").ok();
+        if let Some(unit) = &self.unit {
+            if format == Format::OpenMetrics && matches!(
+                family_name.strip_suffix(unit.as_str()),
+                Some(prefix) if prefix.ends_with('_')
+            ) {
+                buf.write(b"# UNIT ").ok();
+                buf.write(family_name.as_bytes()).ok();
+                buf.write(b" ").ok();
+                buf.write(unit.as_bytes()).ok();
+                buf.write(b"\n").ok();
+            }
+        }
+    }
+
+    /// Writes the sample name with labels
+    fn dump_series<W: IOWrite>(&self, metric_type: MetricType, format: Format, buf: &mut W) {
+        let (_, sample_name) = self.family_and_sample_names(metric_type, format);
+        self.dump_sample_name(&sample_name, buf);
+    }
+
+    /// OpenMetrics requires a suffix for the samples of some types
+    /// which must not be a part of the metric family name
+    fn family_and_sample_names(
+        &self, metric_type: MetricType, format: Format
+    ) -> (Cow<'_, str>, Cow<'_, str>) {
+        let suffix = match (metric_type, format) {
+            (MetricType::Counter, Format::OpenMetrics) => "_total",
+            (MetricType::Info, _) => "_info",
+            _ => return (Cow::Borrowed(&self.name), Cow::Borrowed(&self.name)),
+        };
+        let sample_name = if self.name.ends_with(suffix) {
+            Cow::Borrowed(self.name.as_str())
+        } else {
+            Cow::Owned(format!("{}{}", &self.name, suffix))
+        };
+        match format {
+            Format::OpenMetrics => (
+                Cow::Borrowed(self.name.strip_suffix(suffix).unwrap_or(&self.name)),
+                sample_name,
+            ),
+            Format::Prometheus => (sample_name.clone(), sample_name),
+        }
+    }
+
     fn dump_metric<W: IOWrite>(&self, buf: &mut W) {
-        buf.write(self.name.as_bytes()).ok();
+        self.dump_sample_name(&self.name, buf);
+    }
+
+    /// Writes a sample of a histogram or a summary with an optional `le` or `quantile` label
+    fn distribution_series(&self, suffix: &str, label: Option<(&str, String)>) -> Vec<u8> {
+        let mut sample_metric = Cow::Borrowed(self);
+        if let Some((label_name, label_value)) = label {
+            sample_metric.to_mut().labels.insert(label_name.to_string(), label_value);
+        }
+        let mut buf = vec!();
+        sample_metric.dump_sample_name(&format!("{}{}", &self.name, suffix), &mut buf);
+        buf
+    }
+
+    fn dump_sample_name<W: IOWrite>(&self, sample_name: &str, buf: &mut W) {
+        buf.write(sample_name.as_bytes()).ok();
         if !self.labels.is_empty() {
             buf.write(b"{").ok();
             for (label_ix, (label_name, label_value)) in self.labels.iter().enumerate() {
@@ -347,46 +1363,69 @@ impl ResolvedMetric {
         buf.write(b"\"").ok();
     }
 
-    fn check_value(&self, value: &Value, metric_type: MetricType) -> bool {
+    fn check_value(&self, value: &Value, metric_type: MetricType, format: Format) -> bool {
         use MetricType::*;
 
         match value {
-            Value::Number(_) => true,
-            Value::Bool(_) => {
+            Value::Number(v) => {
                 match metric_type {
                     Gauge | Untyped => true,
-                    Counter => false,
+                    Counter => {
+                        format == Format::Prometheus || matches!(v.as_f64(), Some(v) if v >= 0.0)
+                    }
+                    Info => v.as_f64() == Some(1.0),
+                    StateSet => v.as_f64() == Some(0.0) || v.as_f64() == Some(1.0),
+                    Histogram | Summary => false,
+                }
+            }
+            Value::Bool(v) => {
+                match metric_type {
+                    Gauge | Untyped | StateSet => true,
+                    Info => *v,
+                    Counter | Histogram | Summary => false,
                 }
             }
             Value::String(_) => {
+                // OpenMetrics does not allow anything except numbers
                 match metric_type {
-                    Untyped => true,
-                    Gauge | Counter => false,
+                    Untyped => format == Format::Prometheus,
+                    Gauge | Counter | Info | StateSet | Histogram | Summary => false,
                 }
             }
+            Value::Object(_) => matches!(metric_type, Histogram | Summary),
             _ => false,
         }
     }
+}
 
-    fn dump_value<W: IOWrite>(
-        &self, value: &Value, buf: &mut W
-    ) {
-        match value {
-            Value::Number(v) => {
-                write!(buf, "{}", v).ok();
-            }
-            Value::Bool(v) if *v => {
-                buf.write(b"1").ok();
-            }
-            Value::Bool(_) => {
-                buf.write(b"0").ok();
-            }
-            Value::String(v) => {
-                buf.write(v.as_bytes()).ok();
-            }
-            _ => {
-                unreachable!()
-            }
+fn dump_value<W: IOWrite>(value: &Value, buf: &mut W) {
+    match value {
+        Value::Number(v) => {
+            write!(buf, "{}", v).ok();
+        }
+        Value::Bool(v) if *v => {
+            buf.write(b"1").ok();
+        }
+        Value::Bool(_) => {
+            buf.write(b"0").ok();
+        }
+        Value::String(v) => {
+            buf.write(v.as_bytes()).ok();
+        }
+        _ => {
+            unreachable!()
+        }
+    }
+}
+
+/// Prometheus format requires milliseconds while OpenMetrics uses seconds
+fn dump_timestamp<W: IOWrite>(timestamp: f64, format: Format, buf: &mut W) {
+    match format {
+        Format::Prometheus => {
+            write!(buf, " {}", (timestamp * 1000.0).round() as i64).ok();
+        }
+        Format::OpenMetrics => {
+            write!(buf, " {}", timestamp).ok();
         }
     }
 }
@@ -401,24 +1440,59 @@ impl std::fmt::Display for ResolvedMetric {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::Metrics;
-    use crate::prepare::PreparedMetrics;
-    use super::ResolvedMetric;
+    use crate::config::{DuplicateSeriesPolicy, InvalidNames, MetricType, Metrics};
+    use crate::prepare::{MetricTypeOverrides, PreparedEndpoint, PreparedMetrics};
+    use super::{add_endpoints_status, EndpointStatus, Format, MetricFamilies, ResolvedMetric};
 
     use indoc::indoc;
 
     use serde_json::Value;
     use serde_yaml;
 
+    use std::collections::{BTreeMap, HashMap};
+    use std::time::Duration;
+
+    use url::Url;
+
 
     fn process_with_config(config: &str, data: &str) -> (String, Vec<(log::Level, String)>) {
+        process_with_format(config, data, Format::Prometheus)
+    }
+
+    fn process_with_format(
+        config: &str, data: &str, format: Format
+    ) -> (String, Vec<(log::Level, String)>) {
+        process_with_options(
+            config, data, format, DuplicateSeriesPolicy::First, InvalidNames::Replace
+        )
+    }
+
+    fn process_with_policy(
+        config: &str, data: &str, format: Format, duplicate_series: DuplicateSeriesPolicy
+    ) -> (String, Vec<(log::Level, String)>) {
+        process_with_options(config, data, format, duplicate_series, InvalidNames::Replace)
+    }
+
+    fn process_with_options(
+        config: &str,
+        data: &str,
+        format: Format,
+        duplicate_series: DuplicateSeriesPolicy,
+        invalid_names: InvalidNames,
+    ) -> (String, Vec<(log::Level, String)>) {
         let metrics: Metrics = serde_yaml::from_str(config).expect("parse config");
-        let prepared_metrics = PreparedMetrics::create_from(&metrics.metrics, None).expect("prepare config");
+        let prepared_metrics = PreparedMetrics::create_from(&metrics.metrics, None, None, None)
+            .expect("prepare config");
         let json: Value = serde_json::from_str(data).expect("parse json");
 
         let ctx = ResolvedMetric::default();
+        let mut families = MetricFamilies::new(format, duplicate_series, invalid_names);
+        let warns = prepared_metrics.process(&ctx, &json, &mut families)
+            .warnings.into_iter()
+            .map(|w| (w.level, w.message))
+            .collect();
         let mut buf = vec!();
-        let warns = prepared_metrics.process(&ctx, &json, &mut buf);
+        families.dump(&mut buf);
         (String::from_utf8(buf).expect("utf8 string"), warns)
     }
 
@@ -500,9 +1574,9 @@ mod tests {
             indoc! {r#"
                 # TYPE docs_count gauge
                 docs_count{type="primaries"} 167172864
+                docs_count{type="total"} 334345728
                 # TYPE docs_deleted gauge
                 docs_deleted{type="primaries"} 1345566
-                docs_count{type="total"} 334345728
                 docs_deleted{type="total"} 2825688
             "#}
         );
@@ -543,61 +1617,239 @@ mod tests {
         );
     }
 
-    const CLUSTER_HEALTH_STATS: &'static str = r#"
-        {
-          "cluster_name": "test-cluster",
-          "status": "green",
-          "timed_out": false,
-          "number_of_nodes": 3,
-          "number_of_data_nodes": 3,
-          "active_primary_shards": 680,
-          "active_shards": 1023,
-          "relocating_shards": 0,
-          "initializing_shards": 0,
-          "unassigned_shards": 0,
-          "delayed_unassigned_shards": 0,
-          "number_of_pending_tasks": 0,
-          "number_of_in_flight_fetch": 0,
-          "task_max_waiting_in_queue_millis": 0,
-          "active_shards_percent_as_number": 100.0
-        }
-    "#;
-
     #[test]
-    fn test_selecting_root_node() {
-        let config = indoc! {"
+    fn test_family_across_endpoints() {
+        let primaries_config = indoc! {"
             metrics:
-            # We need to capture a label from a root node
-            - path: ''
+            - path: _all.primaries.docs.*
+              name: docs_${3}
               labels:
-              - name: cluster
-                value: ${$.cluster_name}
-              metrics:
-              - path: number_of_nodes
+              - name: type
+                value: primaries
         "};
-        let (metrics, warns) = process_with_config(config, CLUSTER_HEALTH_STATS);
+        let total_config = indoc! {"
+            metrics:
+            - path: _all.total.docs.*
+              name: docs_${3}
+              labels:
+              - name: type
+                value: total
+        "};
+        let prepared_metrics = [primaries_config, total_config].iter()
+            .map(|config| {
+                let metrics: Metrics = serde_yaml::from_str(config).expect("parse config");
+                PreparedMetrics::create_from(&metrics.metrics, None, None, None)
+                    .expect("prepare config")
+            })
+            .collect::<Vec<_>>();
+        let json: Value = serde_json::from_str(DOCS_STATS).expect("parse json");
+        let ctx = ResolvedMetric::default();
+        let mut families = MetricFamilies::default();
+        for metrics in &prepared_metrics {
+            let stats = metrics.process(&ctx, &json, &mut families);
+            assert_eq!(stats.warnings, vec!());
+        }
+        let mut buf = vec!();
+        families.dump(&mut buf);
         assert_eq!(
-            metrics,
+            String::from_utf8(buf).unwrap(),
             indoc! {r#"
-                # TYPE number_of_nodes gauge
-                number_of_nodes{cluster="test-cluster"} 3
+                # TYPE docs_count gauge
+                docs_count{type="primaries"} 167172864
+                docs_count{type="total"} 334345728
+                # TYPE docs_deleted gauge
+                docs_deleted{type="primaries"} 1345566
+                docs_deleted{type="total"} 2825688
             "#}
         );
-        assert_eq!(warns, vec!());
     }
 
     #[test]
-    fn test_boolean_value() {
+    fn test_duplicate_series() {
         let config = indoc! {"
             metrics:
-            - path: timed_out
+            - path: _all.primaries
+              name: ''
+              metrics:
+              - path: docs.count
+                name: docs
+            - path: _all.*.docs
+              name: ''
+              metrics:
+              - path: count
+                name: docs
         "};
-        let (metrics, warns) = process_with_config(config, CLUSTER_HEALTH_STATS);
+        let warning = |action: &str| {
+            format!(
+                "Duplicate series docs at [_all.primaries.docs.count] and [_all.*.docs.count], {}",
+                action
+            )
+        };
+
+        let (metrics, warns) = process_with_config(config, DOCS_STATS);
         assert_eq!(
             metrics,
             indoc! {r#"
-                # TYPE timed_out gauge
-                timed_out 0
+                # TYPE docs gauge
+                docs 167172864
+            "#}
+        );
+        assert_eq!(
+            warns,
+            vec!(
+                (log::Level::Warn, warning("keeping the first one")),
+                (log::Level::Warn, warning("keeping the first one")),
+            )
+        );
+
+        let (metrics, _) = process_with_policy(
+            config, DOCS_STATS, Format::Prometheus, DuplicateSeriesPolicy::Last
+        );
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE docs gauge
+                docs 334345728
+            "#}
+        );
+
+        let (metrics, _) = process_with_policy(
+            config, DOCS_STATS, Format::Prometheus, DuplicateSeriesPolicy::Sum
+        );
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE docs gauge
+                docs 668691456
+            "#}
+        );
+
+        let (metrics, warns) = process_with_policy(
+            config, DOCS_STATS, Format::Prometheus, DuplicateSeriesPolicy::Error
+        );
+        assert_eq!(metrics, "");
+        assert_eq!(warns[0], (log::Level::Error, warning("dropping the series")));
+
+        let metrics: Metrics = serde_yaml::from_str(config).expect("parse config");
+        let prepared_metrics = PreparedMetrics::create_from(&metrics.metrics, None, None, None)
+            .expect("prepare config");
+        let json: Value = serde_json::from_str(DOCS_STATS).expect("parse json");
+        let mut families = MetricFamilies::new(
+            Format::Prometheus, DuplicateSeriesPolicy::Error, InvalidNames::Replace
+        );
+        let stats = prepared_metrics.process(&ResolvedMetric::default(), &json, &mut families);
+        assert_eq!(stats.series, 0);
+    }
+
+    #[test]
+    fn test_invalid_names() {
+        let config = indoc! {"
+            metrics:
+            - path: indices.*
+            - path: 1m
+        "};
+        let json = indoc! {r#"
+            {
+              "indices": {"Kafka-Topic.v1": 3},
+              "1m": 0.5
+            }
+        "#};
+        let process = |invalid_names| {
+            process_with_options(
+                config, json, Format::Prometheus, DuplicateSeriesPolicy::First, invalid_names
+            )
+        };
+
+        let (metrics, warns) = process(InvalidNames::Replace);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE indices_Kafka_Topic_v1 gauge
+                indices_Kafka_Topic_v1 3
+                # TYPE _1m gauge
+                _1m 0.5
+            "#}
+        );
+        assert_eq!(warns, vec!());
+
+        let (metrics, warns) = process(InvalidNames::SnakeCase);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE indices_kafka_topic_v1 gauge
+                indices_kafka_topic_v1 3
+                # TYPE _1m gauge
+                _1m 0.5
+            "#}
+        );
+        assert_eq!(warns, vec!());
+
+        let (metrics, warns) = process(InvalidNames::Drop);
+        assert_eq!(metrics, "");
+        assert_eq!(
+            warns,
+            vec!(
+                (log::Level::Warn, r#"Invalid metric name: "indices_Kafka-Topic.v1""#.to_string()),
+                (log::Level::Warn, r#"Invalid metric name: "1m""#.to_string()),
+            )
+        );
+    }
+
+    const CLUSTER_HEALTH_STATS: &'static str = r#"
+        {
+          "cluster_name": "test-cluster",
+          "status": "green",
+          "timed_out": false,
+          "number_of_nodes": 3,
+          "number_of_data_nodes": 3,
+          "active_primary_shards": 680,
+          "active_shards": 1023,
+          "relocating_shards": 0,
+          "initializing_shards": 0,
+          "unassigned_shards": 0,
+          "delayed_unassigned_shards": 0,
+          "number_of_pending_tasks": 0,
+          "number_of_in_flight_fetch": 0,
+          "task_max_waiting_in_queue_millis": 0,
+          "active_shards_percent_as_number": 100.0
+        }
+    "#;
+
+    #[test]
+    fn test_selecting_root_node() {
+        let config = indoc! {"
+            metrics:
+            # We need to capture a label from a root node
+            - path: ''
+              labels:
+              - name: cluster
+                value: ${$.cluster_name}
+              metrics:
+              - path: number_of_nodes
+        "};
+        let (metrics, warns) = process_with_config(config, CLUSTER_HEALTH_STATS);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE number_of_nodes gauge
+                number_of_nodes{cluster="test-cluster"} 3
+            "#}
+        );
+        assert_eq!(warns, vec!());
+    }
+
+    #[test]
+    fn test_boolean_value() {
+        let config = indoc! {"
+            metrics:
+            - path: timed_out
+        "};
+        let (metrics, warns) = process_with_config(config, CLUSTER_HEALTH_STATS);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE timed_out gauge
+                timed_out 0
             "#}
         );
         assert_eq!(warns, vec!());
@@ -620,6 +1872,83 @@ mod tests {
         assert_eq!(warns, vec!());
     }
 
+    #[test]
+    fn test_string_value_as_info_and_stateset() {
+        let config = indoc! {"
+            metrics:
+            - path: version
+              type: info
+            - path: health
+              type: stateset
+              value_label: color
+              states: [green, yellow, red]
+            - path: status
+              type: stateset
+              states: [green, yellow, red]
+            - path: mode
+              type: stateset
+              value_label: mode
+            - path: role
+              type: stateset
+              states: [master, data]
+        "};
+        let json = indoc! {r#"
+            {
+              "version": "7.9.0",
+              "health": "yellow",
+              "status": "green",
+              "mode": "sync",
+              "role": "ingest"
+            }
+        "#};
+
+        let (metrics, warns) = process_with_format(config, json, Format::Prometheus);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE version_info gauge
+                version_info{value="7.9.0"} 1
+                # TYPE health gauge
+                health{color="green"} 0
+                health{color="yellow"} 1
+                health{color="red"} 0
+                # TYPE status gauge
+                status{state="green"} 1
+                status{state="yellow"} 0
+                status{state="red"} 0
+                # TYPE mode gauge
+                mode{mode="sync"} 1
+            "#}
+        );
+        assert_eq!(
+            warns,
+            vec!(
+                (log::Level::Warn, r#"Error when dumping metric role: Unknown state: "ingest""#.to_string()),
+            )
+        );
+
+        // a stateset with a label not named after the metric is a gauge,
+        // so the labels do not depend on the format
+        let (metrics, _) = process_with_format(config, json, Format::OpenMetrics);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE version info
+                version_info{value="7.9.0"} 1
+                # TYPE health gauge
+                health{color="green"} 0
+                health{color="yellow"} 1
+                health{color="red"} 0
+                # TYPE status gauge
+                status{state="green"} 1
+                status{state="yellow"} 0
+                status{state="red"} 0
+                # TYPE mode stateset
+                mode{mode="sync"} 1
+            "#}
+        );
+    }
+
     #[test]
     fn test_simplified_metric() {
         let config = indoc! {"
@@ -751,13 +2080,13 @@ mod tests {
               shards_total 1023
               # TYPE indices_shards_docs_count gauge
               indices_shards_docs_count{index=\"catalog\",node=\"kVLufQsXRL-q9l5KN42RIQ\",shard=\"0\"} 71317
+              indices_shards_docs_count{index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"0\"} 71317
+              indices_shards_docs_count{index=\"catalog\",node=\"kVLufQsXRL-q9l5KN42RIQ\",shard=\"1\"} 7471
+              indices_shards_docs_count{index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"1\"} 7471
               # TYPE indices_shards_docs_deleted gauge
               indices_shards_docs_deleted{index=\"catalog\",node=\"kVLufQsXRL-q9l5KN42RIQ\",shard=\"0\"} 7724
-              indices_shards_docs_count{index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"0\"} 71317
               indices_shards_docs_deleted{index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"0\"} 9410
-              indices_shards_docs_count{index=\"catalog\",node=\"kVLufQsXRL-q9l5KN42RIQ\",shard=\"1\"} 7471
               indices_shards_docs_deleted{index=\"catalog\",node=\"kVLufQsXRL-q9l5KN42RIQ\",shard=\"1\"} 4
-              indices_shards_docs_count{index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"1\"} 7471
               indices_shards_docs_deleted{index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"1\"} 4
             "}
         );
@@ -793,19 +2122,19 @@ mod tests {
               # TYPE indices_shards_search_query_time_in_millis counter
               indices_shards_search_query_time_in_millis\
                 {index=\"catalog\",node=\"kVLufQsXRL-q9l5KN42RIQ\",shard=\"0\"} 385
+              indices_shards_search_query_time_in_millis\
+                {index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"0\"} 902
+              indices_shards_search_query_time_in_millis\
+                {index=\"catalog\",node=\"kVLufQsXRL-q9l5KN42RIQ\",shard=\"1\"} 533
+              indices_shards_search_query_time_in_millis\
+                {index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"1\"} 351
               # TYPE indices_shards_search_query_total counter
               indices_shards_search_query_total\
                 {index=\"catalog\",node=\"kVLufQsXRL-q9l5KN42RIQ\",shard=\"0\"} 8
-              indices_shards_search_query_time_in_millis\
-                {index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"0\"} 902
               indices_shards_search_query_total\
                 {index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"0\"} 9
-              indices_shards_search_query_time_in_millis\
-                {index=\"catalog\",node=\"kVLufQsXRL-q9l5KN42RIQ\",shard=\"1\"} 533
               indices_shards_search_query_total\
                 {index=\"catalog\",node=\"kVLufQsXRL-q9l5KN42RIQ\",shard=\"1\"} 6
-              indices_shards_search_query_time_in_millis\
-                {index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"1\"} 351
               indices_shards_search_query_total\
                 {index=\"catalog\",node=\"g4x8KHe2TS2m7gxlPhwk8g\",shard=\"1\"} 9
             "}
@@ -813,6 +2142,226 @@ mod tests {
         assert_eq!(warns, vec!());
     }
 
+    #[test]
+    fn test_when_condition() {
+        let config = indoc! {"
+            metrics:
+            - path: indices.*.shards.*.*
+              name: shard
+              when:
+                path: routing.primary
+              labels:
+              - name: shard
+                value: $3
+              metrics:
+              - path: docs.count
+                name: docs
+            - path: _shards
+              when:
+                path: failed
+              metrics:
+              - total
+            - path: _shards
+              when:
+                path: successful
+                modifiers:
+                - name: eq
+                  args: 1023
+              metrics:
+              - successful
+        "};
+        let (metrics, warns) = process_with_config(config, INDICES_STATS);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE shard_docs gauge
+                shard_docs{shard="0"} 71317
+                shard_docs{shard="1"} 7471
+                # TYPE _shards_successful gauge
+                _shards_successful 1023
+            "#}
+        );
+        assert_eq!(warns, vec!());
+    }
+
+    #[test]
+    fn test_metric_type_overrides() {
+        let config = indoc! {"
+            metrics:
+            - path: thread_pool.*
+              name: thread_pool_${1}
+              metrics:
+              - completed
+              - rejected
+              - queue
+            - path: uptime
+              type: counter
+        "};
+        let json = indoc! {r#"
+            {
+              "thread_pool": {
+                "search": {"completed": 120, "rejected": 3, "queue": 1}
+              },
+              "uptime": 3600
+            }
+        "#};
+        let overrides: HashMap<MetricType, Vec<String>> = serde_yaml::from_str(indoc! {r#"
+            counter:
+            - es_thread_pool_.*_(completed|rejected)
+            untyped:
+            - uptime
+        "#}).expect("parse overrides");
+        let overrides = MetricTypeOverrides::create_from(&overrides, Some("es"))
+            .expect("prepare overrides");
+        let metrics: Metrics = serde_yaml::from_str(config).expect("parse config");
+        let prepared_metrics = PreparedMetrics::create_from(&metrics.metrics, None, None, None)
+            .expect("prepare config");
+        let json: Value = serde_json::from_str(json).expect("parse json");
+
+        let ctx = ResolvedMetric::new_root("es".to_string(), BTreeMap::new());
+        let mut families = MetricFamilies::default().with_type_overrides(&overrides);
+        let warns = prepared_metrics.process(&ctx, &json, &mut families)
+            .warnings;
+        let mut buf = vec!();
+        families.dump(&mut buf);
+        assert_eq!(
+            String::from_utf8(buf).expect("utf8 string"),
+            indoc! {"
+                # TYPE es_thread_pool_search_completed counter
+                es_thread_pool_search_completed 120
+                # TYPE es_thread_pool_search_rejected counter
+                es_thread_pool_search_rejected 3
+                # TYPE es_thread_pool_search_queue gauge
+                es_thread_pool_search_queue 1
+                # TYPE es_uptime untyped
+                es_uptime 3600
+            "}
+        );
+        assert_eq!(warns, vec!());
+
+        let overrides: HashMap<MetricType, Vec<String>> = serde_yaml::from_str(indoc! {"
+            info:
+            - version
+        "}).expect("parse overrides");
+        assert!(MetricTypeOverrides::create_from(&overrides, None).is_err());
+
+        let overrides: HashMap<MetricType, Vec<String>> = serde_yaml::from_str(indoc! {"
+            counter:
+            - uptime
+            untyped:
+            - uptime
+        "}).expect("parse overrides");
+        assert!(MetricTypeOverrides::create_from(&overrides, None).is_err());
+
+        // regular expressions of gauges are checked before counters and untyped
+        let overrides: HashMap<MetricType, Vec<String>> = serde_yaml::from_str(indoc! {"
+            untyped:
+            - .*_queue
+            counter:
+            - thread_pool_.*
+            gauge:
+            - thread_pool_.*_queue
+        "}).expect("parse overrides");
+        let overrides = MetricTypeOverrides::create_from(&overrides, None)
+            .expect("prepare overrides");
+        assert_eq!(overrides.find("thread_pool_search_queue"), Some(MetricType::Gauge));
+        assert_eq!(overrides.find("thread_pool_search_completed"), Some(MetricType::Counter));
+        assert_eq!(overrides.find("indexing_queue"), Some(MetricType::Untyped));
+    }
+
+    #[test]
+    fn test_histogram_and_summary() {
+        let config = indoc! {"
+            metrics:
+            - path: latency
+              name: latency_ms
+              type: histogram
+              buckets: buckets
+              sum: sum_ms
+              count: count
+            - path: percentiles.*
+              name: query_ms
+              type: summary
+              quantiles: values
+              sum: sum
+              count: count
+              labels:
+              - name: kind
+                value: $1
+            - path: broken
+              type: histogram
+              buckets: buckets
+            - path: slow
+              type: summary
+              quantiles: values
+              percentiles: true
+        "};
+        let json = indoc! {r#"
+            {
+              "latency": {
+                "buckets": {"100": 3, "10": 1, "1000": 5},
+                "sum_ms": 1234.5,
+                "count": 6
+              },
+              "percentiles": {
+                "es": {"values": {"1.0": 1, "5.0": 2, "50.0": 12, "99.0": 80, "99.9": 95, "99.99": 99}},
+                "kafka": {"values": {"p50": 10, "p99": 50, "p999": 90, "p9999": 120}},
+                "search": {"values": {"99.0": 80.5, "50.0": 12}, "sum": 300, "count": 6}
+              },
+              "broken": {
+                "buckets": {"fast": 1}
+              },
+              "slow": {
+                "values": {"1.0": 30}
+              }
+            }
+        "#};
+        let (metrics, warns) = process_with_config(config, json);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE latency_ms histogram
+                latency_ms_bucket{le="10.0"} 1
+                latency_ms_bucket{le="100.0"} 3
+                latency_ms_bucket{le="1000.0"} 5
+                latency_ms_bucket{le="+Inf"} 6
+                latency_ms_sum 1234.5
+                latency_ms_count 6
+                # TYPE query_ms summary
+                query_ms{kind="es",quantile="0.01"} 1
+                query_ms{kind="es",quantile="0.05"} 2
+                query_ms{kind="es",quantile="0.5"} 12
+                query_ms{kind="es",quantile="0.99"} 80
+                query_ms{kind="es",quantile="0.999"} 95
+                query_ms{kind="es",quantile="0.9999"} 99
+                query_ms{kind="kafka",quantile="0.5"} 10
+                query_ms{kind="kafka",quantile="0.99"} 50
+                query_ms{kind="kafka",quantile="0.999"} 90
+                query_ms{kind="kafka",quantile="0.9999"} 120
+                query_ms{kind="search",quantile="0.5"} 12
+                query_ms{kind="search",quantile="0.99"} 80.5
+                query_ms_sum{kind="search"} 300
+                query_ms_count{kind="search"} 6
+                # TYPE slow summary
+                slow{quantile="0.01"} 30
+            "#}
+        );
+        assert_eq!(
+            warns,
+            vec!(
+                (log::Level::Warn, r#"Error when dumping metric broken: Cannot parse bound: "fast""#.to_string()),
+            )
+        );
+
+        let metrics: Metrics = serde_yaml::from_str(indoc! {"
+            metrics:
+            - path: latency
+              type: summary
+              buckets: buckets
+        "}).expect("parse config");
+        assert!(PreparedMetrics::create_from(&metrics.metrics, None, None, None).is_err());
+    }
+
     #[test]
     fn test_multiply_filter() {
         let config = indoc! {"
@@ -846,4 +2395,968 @@ mod tests {
         );
         assert_eq!(warns, vec!());
     }
+
+    #[test]
+    fn test_parse_filters() {
+        let config = indoc! {"
+            metrics:
+            - path: indices.*
+              name: index
+              labels:
+              - name: index
+                value: $1
+              metrics:
+              - path: uptime
+                modifiers:
+                - name: parse_duration
+              - path: took
+                modifiers:
+                - name: parse_duration
+              - path: refresh_interval
+                modifiers:
+                - name: parse_duration
+              - path: flush_time
+                modifiers:
+                - name: parse_duration
+                  args: ms
+              - path: store_size
+                modifiers:
+                - name: parse_bytes
+              - path: heap_percent
+                modifiers:
+                - name: parse_percent
+              - path: docs_count
+                modifiers:
+                - name: to_number
+        "};
+        let json = indoc! {r#"
+            {
+              "indices": {
+                "products": {
+                  "uptime": "3.2d",
+                  "took": "12ms",
+                  "refresh_interval": "1h30m",
+                  "flush_time": 250,
+                  "store_size": "1.5gb",
+                  "heap_percent": "73%",
+                  "docs_count": "1024"
+                },
+                "orders": {
+                  "uptime": "long",
+                  "store_size": "10zb",
+                  "docs_count": "many"
+                }
+              }
+            }
+        "#};
+        let (metrics, warns) = process_with_config(config, json);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE index_uptime gauge
+                index_uptime{index="products"} 276480
+                # TYPE index_took gauge
+                index_took{index="products"} 0.012
+                # TYPE index_refresh_interval gauge
+                index_refresh_interval{index="products"} 5400
+                # TYPE index_flush_time gauge
+                index_flush_time{index="products"} 0.25
+                # TYPE index_store_size gauge
+                index_store_size{index="products"} 1610612736
+                # TYPE index_heap_percent gauge
+                index_heap_percent{index="products"} 0.73
+                # TYPE index_docs_count gauge
+                index_docs_count{index="products"} 1024
+            "#}
+        );
+        assert_eq!(
+            warns,
+            vec!(
+                (log::Level::Warn, r#"Error when applying filter: Cannot parse number: "long""#.to_string()),
+                (log::Level::Warn, r#"Error when applying filter: Unknown size unit in "10zb""#.to_string()),
+                (log::Level::Warn, r#"Error when applying filter: Cannot convert to a number: "many""#.to_string()),
+            )
+        );
+    }
+
+    #[test]
+    fn test_time_filters_and_timestamps() {
+        let config = indoc! {"
+            metrics:
+            - path: snapshots.*
+              name: snapshot
+              timestamp:
+                path: end_time_in_millis
+                modifiers:
+                - name: parse_time
+                  args: epoch_ms
+              labels:
+              - name: snapshot
+                value: $1
+              metrics:
+              - path: start_time
+                modifiers:
+                - name: parse_time
+              - path: end_time
+                modifiers:
+                - name: parse_time
+                  args: '%Y-%m-%d %H:%M:%S'
+              - path: date
+                modifiers:
+                - name: parse_time
+                  args: '%Y-%m-%d'
+              - path: start_time_in_millis
+                name: start
+                modifiers:
+                - name: parse_time
+                  args: epoch_ms
+        "};
+        let json = indoc! {r#"
+            {
+              "snapshots": {
+                "daily": {
+                  "start_time": "2020-10-01T12:00:00.5+02:00",
+                  "end_time": "2020-10-01 10:30:00",
+                  "date": "2020-10-01",
+                  "start_time_in_millis": 1601546400500,
+                  "end_time_in_millis": 1601548200000
+                }
+              }
+            }
+        "#};
+
+        let (metrics, warns) = process_with_format(config, json, Format::Prometheus);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE snapshot_start_time gauge
+                snapshot_start_time{snapshot="daily"} 1601546400.5 1601548200000
+                # TYPE snapshot_end_time gauge
+                snapshot_end_time{snapshot="daily"} 1601548200 1601548200000
+                # TYPE snapshot_date gauge
+                snapshot_date{snapshot="daily"} 1601510400 1601548200000
+                # TYPE snapshot_start gauge
+                snapshot_start{snapshot="daily"} 1601546400.5 1601548200000
+            "#}
+        );
+        assert_eq!(warns, vec!());
+
+        let (metrics, _) = process_with_format(config, json, Format::OpenMetrics);
+        assert!(metrics.contains(indoc! {r#"
+            snapshot_date{snapshot="daily"} 1601510400 1601548200
+        "#}));
+
+        let config = indoc! {"
+            metrics:
+            - path: created
+              modifiers:
+              - name: parse_time
+              - name: age
+        "};
+        let (metrics, warns) = process_with_config(config, r#"{"created": "2000-01-01T00:00:00Z"}"#);
+        let age: f64 = metrics.lines().last()
+            .and_then(|line| line.strip_prefix("created "))
+            .expect("age sample")
+            .parse().expect("age value");
+        assert!(age > 6e8);
+        assert_eq!(warns, vec!());
+    }
+
+    #[test]
+    fn test_regex_filters() {
+        let config = indoc! {r#"
+            metrics:
+            - path: nodes.*
+              name: node
+              labels:
+              - name: jvm_vendor
+                value: ${ $.jvm }
+                modifiers:
+                - name: regex_replace
+                  args: ['\s*[\d.]+$', '']
+              - name: es_version
+                value: ${ $.version }
+                modifiers:
+                - name: regex_extract
+                  args:
+                    pattern: '^(?P<major>\d+)\.(?P<minor>\d+)'
+                    group: major
+              - name: es_minor_version
+                value: ${ $.version }
+                modifiers:
+                - name: regex_extract
+                  args: '^(\d+\.\d+)'
+              metrics:
+              - path: jvm
+                name: jvm_openjdk
+                modifiers:
+                - name: regex_match
+                  args: '^OpenJDK'
+              - path: version
+                name: minor_version
+                modifiers:
+                - name: regex_extract
+                  args: ['^(\d+)\.(\d+)', 2]
+                - name: to_number
+              - path: heap
+                name: heap_max_bytes
+                modifiers:
+                - name: regex_extract
+                  args:
+                    pattern: 'max=(\d+)'
+                    number: true
+              - path: build
+                name: build
+                type: info
+                modifiers:
+                - name: regex_extract
+                  args: '#(\d+)'
+        "#};
+        let json = indoc! {r#"
+            {
+              "nodes": {
+                "es-1": {
+                  "jvm": "OpenJDK 11.0.9",
+                  "version": "7.10.2",
+                  "heap": "init=512 max=1024",
+                  "build": "#007"
+                }
+              }
+            }
+        "#};
+        let (metrics, warns) = process_with_config(config, json);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE node_jvm_openjdk gauge
+                node_jvm_openjdk{es_minor_version="7.10",es_version="7",jvm_vendor="OpenJDK"} 1
+                # TYPE node_minor_version gauge
+                node_minor_version{es_minor_version="7.10",es_version="7",jvm_vendor="OpenJDK"} 10
+                # TYPE node_heap_max_bytes gauge
+                node_heap_max_bytes{es_minor_version="7.10",es_version="7",jvm_vendor="OpenJDK"} 1024
+                # TYPE node_build_info gauge
+                node_build_info{es_minor_version="7.10",es_version="7",jvm_vendor="OpenJDK",value="007"} 1
+            "#}
+        );
+        assert_eq!(warns, vec!());
+
+        for args in &[r#"['(\d+)', 3]"#, r#"{pattern: '(\d+)', number: 'yes'}"#] {
+            let config = format!(
+                "metrics:\n- path: version\n  modifiers:\n  - name: regex_extract\n    args: {}\n",
+                args
+            );
+            let metrics: Metrics = serde_yaml::from_str(&config).expect("parse config");
+            assert!(PreparedMetrics::create_from(&metrics.metrics, None, None, None).is_err());
+        }
+    }
+
+    #[test]
+    fn test_map_filter() {
+        let config = indoc! {"
+            metrics:
+            - path: clusters.*
+              name: cluster
+              labels:
+              - name: cluster
+                value: $1
+              - name: tier
+                value: ${ $.replicas }
+                modifiers:
+                - name: map
+                  args:
+                    values:
+                      '0': none
+                      '1': single
+                    default: multiple
+              metrics:
+              - path: status
+                modifiers:
+                - name: map
+                  args:
+                    green: 0
+                    yellow: 1
+                    red: 2
+              - path: read_only
+                modifiers:
+                - name: map
+                  args:
+                    'true': 1
+                    'false': 0
+        "};
+        let json = indoc! {r#"
+            {
+              "clusters": {
+                "a": {"status": "yellow", "replicas": 1, "read_only": false},
+                "b": {"status": "purple", "replicas": 3, "read_only": true}
+              }
+            }
+        "#};
+        let (metrics, warns) = process_with_config(config, json);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE cluster_status gauge
+                cluster_status{cluster="a",tier="single"} 1
+                # TYPE cluster_read_only gauge
+                cluster_read_only{cluster="a",tier="single"} 0
+                cluster_read_only{cluster="b",tier="multiple"} 1
+            "#}
+        );
+        assert_eq!(
+            warns,
+            vec!(
+                (log::Level::Warn, r#"Error when applying filter: No mapping for "purple""#.to_string()),
+            )
+        );
+    }
+
+    #[test]
+    fn test_comparison_and_arithmetic_filters() {
+        let config = indoc! {"
+            metrics:
+            - path: heap_used_percent
+              name: heap_high
+              modifiers:
+              - name: gt
+                args: 80
+            - path: heap_used_percent
+              name: heap_low
+              modifiers:
+              - name: le
+                args:
+                  value: 50
+            - path: heap_used_percent
+              name: heap_ok
+              modifiers:
+              - name: between
+                args: [0, 90]
+            - path: name
+              name: not_master
+              modifiers:
+              - name: ne
+                args: es-master-1
+            - path: name
+              name: second_data_node
+              modifiers:
+              - name: ge
+                args: es-data-2
+            - path: zone
+              name: zone_ab
+              modifiers:
+              - name: in
+                args: [a, b]
+            - path: load
+              modifiers:
+              - name: abs
+            - path: latency
+              name: latency_rounded
+              modifiers:
+              - name: round
+                args: 2
+            - path: latency
+              name: latency_floor
+              modifiers:
+              - name: floor
+            - path: latency
+              name: latency_ceil
+              modifiers:
+              - name: ceil
+            - path: ratio
+              name: ratio_clamped
+              modifiers:
+              - name: clamp
+                args:
+                  max: 1
+            - path: ratio
+              name: ratio_squared
+              modifiers:
+              - name: pow
+                args:
+                  exponent: 2
+            - path: count
+              name: count_log
+              modifiers:
+              - name: log
+                args: 2
+            - path: count
+              name: count_shifted
+              modifiers:
+              - name: sub
+                args: 1
+              - name: add
+                args:
+                  value: 0.5
+            - path: missing
+              modifiers:
+              - name: gt
+                args: 0
+        "};
+        let json = indoc! {r#"
+            {
+              "heap_used_percent": 85,
+              "name": "es-data-2",
+              "zone": "b",
+              "load": -0.75,
+              "latency": 12.3456,
+              "ratio": 1.25,
+              "count": 1024,
+              "missing": null
+            }
+        "#};
+        let (metrics, warns) = process_with_config(config, json);
+        assert_eq!(
+            metrics,
+            indoc! {"
+                # TYPE heap_high gauge
+                heap_high 1
+                # TYPE heap_low gauge
+                heap_low 0
+                # TYPE heap_ok gauge
+                heap_ok 1
+                # TYPE not_master gauge
+                not_master 1
+                # TYPE second_data_node gauge
+                second_data_node 1
+                # TYPE zone_ab gauge
+                zone_ab 1
+                # TYPE load gauge
+                load 0.75
+                # TYPE latency_rounded gauge
+                latency_rounded 12.35
+                # TYPE latency_floor gauge
+                latency_floor 12
+                # TYPE latency_ceil gauge
+                latency_ceil 13
+                # TYPE ratio_clamped gauge
+                ratio_clamped 1
+                # TYPE ratio_squared gauge
+                ratio_squared 1.5625
+                # TYPE count_log gauge
+                count_log 10
+                # TYPE count_shifted gauge
+                count_shifted 1023.5
+                # TYPE missing gauge
+                missing 0
+            "}
+        );
+        assert_eq!(warns, vec!());
+
+        let invalid_args = &[
+            ("log", "1"),
+            ("log", "0"),
+            ("log", "{value: 2}"),
+            ("mul", "{value: 2}"),
+            ("mul", "{factor: 2, divisor: 3}"),
+            ("div", "{factor: 2}"),
+            ("div", "[]"),
+            ("add", "{factor: 1}"),
+            ("add", "[1, 2]"),
+            ("sub", "one"),
+            ("round", "16"),
+            ("round", "-1"),
+            ("round", "1.5"),
+            ("round", "{digits: 1000}"),
+        ];
+        for (filter, args) in invalid_args {
+            let config = format!(
+                "metrics:\n- path: count\n  modifiers:\n  - name: {}\n    args: {}\n",
+                filter, args
+            );
+            let metrics: Metrics = serde_yaml::from_str(&config).expect("parse config");
+            assert!(PreparedMetrics::create_from(&metrics.metrics, None, None, None).is_err());
+        }
+    }
+
+    #[test]
+    fn test_aggregation_filters() {
+        let config = indoc! {"
+            metrics:
+            - path: tasks
+              name: pending_tasks
+              modifiers:
+              - name: len
+            - path: tasks
+              name: high_priority_tasks
+              modifiers:
+              - name: count_where
+                args:
+                  path: priority
+                  value: HIGH
+            - path: shards
+              name: shards_size
+              modifiers:
+              - name: sum
+                args: size
+            - path: shards
+              name: shards_avg_size
+              modifiers:
+              - name: avg
+                args:
+                  path: size
+            - path: shards
+              name: primary_shards
+              modifiers:
+              - name: count_where
+                args:
+                  path: primary
+            - path: thread_pool
+              name: thread_pool_max_queue
+              modifiers:
+              - name: max
+                args: queue
+            - path: thread_pool
+              name: thread_pool_min_queue
+              modifiers:
+              - name: min
+                args: queue
+            - path: relocating_shards
+              modifiers:
+              - name: sum
+        "};
+        let json = indoc! {r#"
+            {
+              "tasks": [{"priority": "HIGH"}, {"priority": "NORMAL"}, {"priority": "HIGH"}],
+              "shards": [
+                {"size": 100, "primary": true},
+                {"size": 250, "primary": false},
+                {"size": null, "primary": true}
+              ],
+              "thread_pool": {
+                "search": {"queue": 3},
+                "write": {"queue": 12},
+                "get": {"queue": 0}
+              },
+              "relocating_shards": []
+            }
+        "#};
+        let (metrics, warns) = process_with_config(config, json);
+        assert_eq!(
+            metrics,
+            indoc! {"
+                # TYPE pending_tasks gauge
+                pending_tasks 3
+                # TYPE high_priority_tasks gauge
+                high_priority_tasks 2
+                # TYPE shards_size gauge
+                shards_size 350
+                # TYPE shards_avg_size gauge
+                shards_avg_size 175
+                # TYPE primary_shards gauge
+                primary_shards 2
+                # TYPE thread_pool_max_queue gauge
+                thread_pool_max_queue 12
+                # TYPE thread_pool_min_queue gauge
+                thread_pool_min_queue 0
+                # TYPE relocating_shards gauge
+                relocating_shards 0
+            "}
+        );
+        assert_eq!(warns, vec!());
+    }
+
+    #[test]
+    fn test_label_modifiers() {
+        let config = indoc! {"
+            metrics:
+            - path: nodes.*
+              name: node
+              labels:
+              - name: master
+                value: ${ $.roles }
+                modifiers:
+                - name: contains
+                  args: master
+              - name: ingest
+                value: ${ $.settings.ingest }
+                modifiers:
+                - name: exists
+              - name: voting_only
+                value: ${ $.voting_only }
+                modifiers:
+                - name: not
+              metrics:
+              - path: jvm.heap_used_percent
+                name: heap_used_percent
+              - path: jvm.gc_enabled
+                name: gc_enabled
+                modifiers:
+                - name: bool
+        "};
+        let json = indoc! {r#"
+            {
+              "nodes": {
+                "es-1": {
+                  "roles": ["master", "data"],
+                  "settings": {"ingest": false},
+                  "voting_only": 1,
+                  "jvm": {"heap_used_percent": 73, "gc_enabled": "yes"}
+                },
+                "es-2": {
+                  "roles": ["data"],
+                  "settings": {},
+                  "voting_only": 0,
+                  "jvm": {"heap_used_percent": 42, "gc_enabled": ""}
+                }
+              }
+            }
+        "#};
+        let (metrics, warns) = process_with_config(config, json);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE node_heap_used_percent gauge
+                node_heap_used_percent{ingest="true",master="true",voting_only="false"} 73
+                node_heap_used_percent{ingest="false",master="false",voting_only="true"} 42
+                # TYPE node_gc_enabled gauge
+                node_gc_enabled{ingest="true",master="true",voting_only="false"} 1
+                node_gc_enabled{ingest="false",master="false",voting_only="true"} 0
+            "#}
+        );
+        assert_eq!(warns, vec!());
+    }
+
+    #[test]
+    fn test_parent_modifiers() {
+        let config = indoc! {"
+            metrics:
+            - path: indices.*
+              name: index
+              labels:
+              - name: index
+                value: $1
+              modifiers:
+              - name: select
+                args: state
+              - name: eq
+                args: open
+              metrics:
+              - docs.count
+            - path: nodes.*
+              name: node
+              labels:
+              - name: node
+                value: $1
+              modifiers:
+              - name: select
+                args: stats
+              metrics:
+              - docs
+        "};
+        let json = indoc! {r#"
+            {
+              "indices": {
+                "catalog": {"state": "open", "docs": {"count": 10}},
+                "catalog_old": {"state": "close", "docs": {"count": 20}}
+              },
+              "nodes": {
+                "es-1": {"stats": {"docs": 5}}
+              }
+            }
+        "#};
+        let (metrics, warns) = process_with_config(config, json);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE index_docs_count gauge
+                index_docs_count{index="catalog"} 10
+                # TYPE node_docs gauge
+                node_docs{node="es-1"} 5
+            "#}
+        );
+        assert_eq!(warns, vec!());
+    }
+
+    #[test]
+    fn test_endpoints_status() {
+        let health_endpoint = PreparedEndpoint {
+            id: None,
+            url: Url::parse("http://example.com/_cluster/health").unwrap(),
+            auth: None,
+            name: "cluster_health".to_string(),
+            condition: None,
+            metrics: PreparedMetrics(vec!()),
+        };
+        let nodes_endpoint = PreparedEndpoint {
+            id: Some("nodes".to_string()),
+            url: Url::parse("http://example.com/_nodes/stats").unwrap(),
+            auth: None,
+            name: "".to_string(),
+            condition: None,
+            metrics: PreparedMetrics(vec!()),
+        };
+        let indices_endpoint = PreparedEndpoint {
+            id: None,
+            url: Url::parse("http://example.com/_all/_stats").unwrap(),
+            auth: None,
+            name: "".to_string(),
+            condition: None,
+            metrics: PreparedMetrics(vec!()),
+        };
+        let mut global_labels = BTreeMap::new();
+        global_labels.insert("cluster".to_string(), "test".to_string());
+        let root_metric = ResolvedMetric::new_root("es".to_string(), global_labels);
+
+        let statuses = [
+            EndpointStatus {
+                endpoint: &health_endpoint,
+                up: true,
+                scrape_duration: Duration::from_millis(15),
+            },
+            EndpointStatus {
+                endpoint: &nodes_endpoint,
+                up: false,
+                scrape_duration: Duration::from_millis(10000),
+            },
+            EndpointStatus {
+                endpoint: &indices_endpoint,
+                up: true,
+                scrape_duration: Duration::from_millis(1500),
+            },
+        ];
+
+        let mut families = MetricFamilies::new(
+            Format::Prometheus, DuplicateSeriesPolicy::First, InvalidNames::Replace
+        );
+        let warnings = add_endpoints_status(&root_metric, &statuses, &mut families);
+        assert_eq!(warnings.len(), 0);
+        let mut buf = vec!();
+        families.dump(&mut buf);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            indoc! {r#"
+                # HELP es_endpoint_up Whether the endpoint was scraped successfully
+                # TYPE es_endpoint_up gauge
+                es_endpoint_up{cluster="test",endpoint="cluster_health"} 1
+                es_endpoint_up{cluster="test",endpoint="nodes"} 0
+                es_endpoint_up{cluster="test",endpoint="/_all/_stats"} 1
+                # HELP es_endpoint_scrape_duration_seconds Time spent to fetch and process the endpoint
+                # TYPE es_endpoint_scrape_duration_seconds gauge
+                es_endpoint_scrape_duration_seconds{cluster="test",endpoint="cluster_health"} 0.015
+                es_endpoint_scrape_duration_seconds{cluster="test",endpoint="nodes"} 10.0
+                es_endpoint_scrape_duration_seconds{cluster="test",endpoint="/_all/_stats"} 1.5
+            "#}
+        );
+
+        // a config metric with the same name shares the family
+        let metrics: Metrics = serde_yaml::from_str(indoc! {"
+            metrics:
+            - path: nodes_up
+              name: endpoint_up
+              labels:
+              - name: endpoint
+                value: nodes
+        "}).expect("parse config");
+        let prepared_metrics = PreparedMetrics::create_from(&metrics.metrics, None, None, None)
+            .expect("prepare config");
+        let json: Value = serde_json::from_str(r#"{"nodes_up": 1}"#).expect("parse json");
+        let mut families = MetricFamilies::new(
+            Format::Prometheus, DuplicateSeriesPolicy::First, InvalidNames::Replace
+        );
+        prepared_metrics.process(&root_metric, &json, &mut families);
+        let warnings = add_endpoints_status(&root_metric, &statuses, &mut families);
+        assert_eq!(
+            warnings.into_iter().map(|w| w.message).collect::<Vec<_>>(),
+            vec!(
+                r#"Duplicate series es_endpoint_up{cluster="test",endpoint="nodes"} at [nodes_up] and [endpoints status], keeping the first one"#.to_string(),
+            )
+        );
+        let mut buf = vec!();
+        families.dump(&mut buf);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            indoc! {r#"
+                # TYPE es_endpoint_up gauge
+                es_endpoint_up{cluster="test",endpoint="nodes"} 1
+                es_endpoint_up{cluster="test",endpoint="cluster_health"} 1
+                es_endpoint_up{cluster="test",endpoint="/_all/_stats"} 1
+                # HELP es_endpoint_scrape_duration_seconds Time spent to fetch and process the endpoint
+                # TYPE es_endpoint_scrape_duration_seconds gauge
+                es_endpoint_scrape_duration_seconds{cluster="test",endpoint="cluster_health"} 0.015
+                es_endpoint_scrape_duration_seconds{cluster="test",endpoint="nodes"} 10.0
+                es_endpoint_scrape_duration_seconds{cluster="test",endpoint="/_all/_stats"} 1.5
+            "#}
+        );
+    }
+
+    #[test]
+    fn test_openmetrics() {
+        let config = indoc! {"
+            metrics:
+            - path: docs_total
+              type: counter
+            - path: flushes
+              type: counter
+            - path: query_time_seconds
+              unit: seconds
+            - path: build
+              type: info
+              labels:
+              - name: version
+                value: 7.9.0
+            - path: health.*
+              name: health
+              type: stateset
+              labels:
+              - name: health
+                value: $1
+            - path: status
+        "};
+        let json = indoc! {r#"
+            {
+              "docs_total": 10,
+              "flushes": 3,
+              "query_time_seconds": 0.5,
+              "build": 1,
+              "health": {"green": true, "red": false},
+              "status": "green"
+            }
+        "#};
+
+        let (metrics, warns) = process_with_format(config, json, Format::OpenMetrics);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE docs counter
+                docs_total 10
+                # TYPE flushes counter
+                flushes_total 3
+                # TYPE query_time_seconds gauge
+                # UNIT query_time_seconds seconds
+                query_time_seconds 0.5
+                # TYPE build info
+                build_info{version="7.9.0"} 1
+                # TYPE health stateset
+                health{health="green"} 1
+                health{health="red"} 0
+            "#}
+        );
+        assert_eq!(warns.len(), 1);
+
+        let (metrics, warns) = process_with_format(config, json, Format::Prometheus);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE docs_total counter
+                docs_total 10
+                # TYPE flushes counter
+                flushes 3
+                # TYPE query_time_seconds gauge
+                query_time_seconds 0.5
+                # TYPE build_info gauge
+                build_info{version="7.9.0"} 1
+                # TYPE health gauge
+                health{health="green"} 1
+                health{health="red"} 0
+                # TYPE status untyped
+                status green
+            "#}
+        );
+        assert_eq!(warns, vec!());
+    }
+
+    #[test]
+    fn test_openmetrics_counter_suffix() {
+        let config = indoc! {"
+            metrics:
+            - path: docs_total
+              type: counter
+            - path: docs
+              type: counter
+            - path: flushes
+              type: counter
+            - path: flushes_total
+        "};
+        let json = indoc! {r#"
+            {
+              "docs_total": 10,
+              "docs": 11,
+              "flushes": 3,
+              "flushes_total": 4
+            }
+        "#};
+
+        let (metrics, warns) = process_with_format(config, json, Format::OpenMetrics);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE docs counter
+                docs_total 10
+                # TYPE flushes counter
+                flushes_total 3
+            "#}
+        );
+        assert_eq!(
+            warns,
+            vec!(
+                (
+                    log::Level::Warn,
+                    "Duplicate series docs_total at [docs_total] and [docs], keeping the first one"
+                        .to_string()
+                ),
+                (
+                    log::Level::Warn,
+                    "Duplicate series flushes_total at [flushes] and [flushes_total], keeping the first one"
+                        .to_string()
+                ),
+            )
+        );
+
+        let (metrics, warns) = process_with_format(config, json, Format::Prometheus);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # TYPE docs_total counter
+                docs_total 10
+                # TYPE docs counter
+                docs 11
+                # TYPE flushes counter
+                flushes 3
+                # TYPE flushes_total gauge
+                flushes_total 4
+            "#}
+        );
+        assert_eq!(warns, vec!());
+    }
+
+    #[test]
+    fn test_help() {
+        let config = indoc! {r#"
+            metrics:
+            - path: _all.*
+              name: ${1}
+              help: Statistics of the ${1} shards
+              metrics:
+              - path: docs.count
+                name: docs_count
+              - path: docs.deleted
+                name: docs_deleted
+                help: |-
+                  Number of "deleted" documents
+                  that are not merged yet, see: C:\docs
+        "#};
+        let (metrics, warns) = process_with_config(config, DOCS_STATS);
+        assert_eq!(
+            metrics,
+            indoc! {r#"
+                # HELP primaries_docs_count Statistics of the primaries shards
+                # TYPE primaries_docs_count gauge
+                primaries_docs_count 167172864
+                # HELP total_docs_count Statistics of the total shards
+                # TYPE total_docs_count gauge
+                total_docs_count 334345728
+                # HELP primaries_docs_deleted Number of "deleted" documents\nthat are not merged yet, see: C:\\docs
+                # TYPE primaries_docs_deleted gauge
+                primaries_docs_deleted 1345566
+                # HELP total_docs_deleted Number of "deleted" documents\nthat are not merged yet, see: C:\\docs
+                # TYPE total_docs_deleted gauge
+                total_docs_deleted 2825688
+            "#}
+        );
+        assert_eq!(warns, vec!());
+
+        let (metrics, _) = process_with_format(config, DOCS_STATS, Format::OpenMetrics);
+        assert!(metrics.contains(indoc! {r#"
+            # HELP total_docs_deleted Number of \"deleted\" documents\nthat are not merged yet, see: C:\\docs
+        "#}));
+    }
 }
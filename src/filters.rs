@@ -1,12 +1,24 @@
 use anyhow::{anyhow, bail, Error as AnyError};
 
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime};
+
 use dyn_clone::DynClone;
 
 use fehler::{throw, throws};
 
+use regex::Regex;
+
 use serde_json::Value;
 
-type BoxedFilter = Box<dyn Filter + Send>;
+use std::cmp::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::prepare::JsonSelector;
+
+/// Rounding to more digits is beyond the precision of f64
+const MAX_ROUND_DIGITS: i32 = 15;
+
+pub(crate) type BoxedFilter = Box<dyn Filter + Send + Sync>;
 
 pub trait Filter: DynClone {
     fn apply(&self, value: &Value) -> Result<Value, AnyError>;
@@ -27,34 +39,53 @@ fn single_scalar_arg(args: &Value) -> Value {
     }
 }
 
+/// Number argument passed alone, in an array or with its name as the keyword
+#[throws(AnyError)]
+fn single_arg_f64(args: &Value, name: &str) -> f64 {
+    match args_f64(args, &[name])?[0] {
+        Some(v) => v,
+        None => bail!("Argument required: {}", name),
+    }
+}
+
+/// Number arguments in the order of the names, `None` when an argument is missing
+#[throws(AnyError)]
+fn args_f64(args: &Value, names: &[&str]) -> Vec<Option<f64>> {
+    named_args(args, names)?.into_iter()
+        .map(|arg| match arg {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::Number(v)) => Ok(v.as_f64()),
+            Some(arg) => Err(anyhow!("Invalid argument: {:?}", arg)),
+        })
+        .collect::<Result<_, AnyError>>()?
+}
+
+/// Returns positional or keyword arguments in the order of the names,
+/// a single scalar argument is the first one
 #[throws(AnyError)]
-fn single_arg_f64(args: &Value, map_key: Option<&str>) -> f64 {
+fn named_args<'a>(args: &'a Value, names: &[&str]) -> Vec<Option<&'a Value>> {
     match args {
-        Value::Number(f) => f.as_f64().unwrap(),
         Value::Array(seq) => {
-            match seq.as_slice() {
-                [Value::Number(ref f)] => f.as_f64().unwrap(),
-                _ => bail!("Invalid argument: {:?}", args),
+            if seq.len() > names.len() {
+                bail!("Too many arguments: {:?}", args);
             }
+            (0..names.len()).map(|ix| seq.get(ix)).collect()
         }
         Value::Object(map) => {
-            if let Some(map_key) = map_key {
-                if map.len() > 1 {
-                    bail!("Too many arguments: {:?}", args);
-                }
-                match map.get(map_key) {
-                    Some(Value::Number(f)) => f.as_f64().unwrap(),
-                    _ => bail!("Invalid argument: {:?}", args),
-                }
-            } else {
-                bail!("Keyword arguments is not supported");
+            if let Some(key) = map.keys().find(|key| !names.contains(&key.as_str())) {
+                bail!("Unknown argument: {}", key);
             }
+            names.iter().map(|name| map.get(*name)).collect()
+        }
+        Value::Null => vec!(None; names.len()),
+        _ => {
+            let mut named_args = vec!(None; names.len());
+            named_args[0] = Some(args);
+            named_args
         }
-        _ => bail!("Invalid arguments: {:?}", args),
     }
 }
 
-#[allow(dead_code)]
 #[throws(AnyError)]
 fn check_no_args(args: &Value) -> () {
     match args {
@@ -99,8 +130,8 @@ impl Multiply {
     #[throws(AnyError)]
     pub fn create(args: &Value) -> BoxedFilter {
         Box::new(Self {
-            factor: single_arg_f64(args, Some("factor"))?
-        }) as Box<dyn Filter + Send>
+            factor: single_arg_f64(args, "factor")?
+        }) as BoxedFilter
     }
 }
 
@@ -125,8 +156,8 @@ impl Divide {
     #[throws(AnyError)]
     pub fn create(args: &Value) -> BoxedFilter {
         Box::new(Self {
-            denominator: single_arg_f64(args, Some("divisor"))?
-        }) as Box<dyn Filter + Send>
+            denominator: single_arg_f64(args, "divisor")?
+        }) as BoxedFilter
     }
 }
 
@@ -152,22 +183,1194 @@ impl Equal {
     pub fn create(args: &Value) -> BoxedFilter {
         Box::new(Self {
             value: single_scalar_arg(args)?
-        }) as Box<dyn Filter + Send>
+        }) as BoxedFilter
     }
 }
 
 impl Filter for Equal {
     #[throws(AnyError)]
     fn apply(&self, value: &Value) -> Value {
-        use Value::*;
+        Value::from(scalars_equal(&self.value, value))
+    }
+}
+
+fn scalars_equal(v1: &Value, v2: &Value) -> bool {
+    use Value::*;
+
+    match (v1, v2) {
+        (String(v1), String(v2)) if v1 == v2 => true,
+        (Number(v1), Number(v2)) if v1 == v2 => true,
+        (Bool(v1), Bool(v2)) if v1 == v2 => true,
+        (Null, Null) => true,
+        // TODO: Implement equality for arrays and objects
+        _ => false,
+    }
+}
+
+/// Empty strings, `"false"` string, zero numbers, empty arrays and objects are falsy
+pub(crate) fn is_truthy(value: &Value) -> bool {
+    match value {
+        Value::Null => false,
+        Value::Bool(v) => *v,
+        Value::Number(v) => v.as_f64() != Some(0.0),
+        Value::String(v) => !v.is_empty() && v != "false",
+        Value::Array(v) => !v.is_empty(),
+        Value::Object(v) => !v.is_empty(),
+    }
+}
+
+#[derive(Clone)]
+pub struct Bool;
+
+impl Bool {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        check_no_args(args)?;
+        Box::new(Self) as BoxedFilter
+    }
+}
+
+impl Filter for Bool {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        Value::from(is_truthy(value))
+    }
+}
+
+#[derive(Clone)]
+pub struct Not;
+
+impl Not {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        check_no_args(args)?;
+        Box::new(Self) as BoxedFilter
+    }
+}
+
+impl Filter for Not {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        Value::from(!is_truthy(value))
+    }
+}
+
+#[derive(Clone)]
+pub struct Exists;
+
+impl Exists {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        check_no_args(args)?;
+        Box::new(Self) as BoxedFilter
+    }
+}
+
+impl Filter for Exists {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        Value::from(!value.is_null())
+    }
+}
+
+/// Checks if a string contains a substring, an array contains an element
+/// or an object contains a key
+#[derive(Clone)]
+pub struct Contains {
+    value: Value,
+}
+
+impl Contains {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        Box::new(Self {
+            value: single_scalar_arg(args)?
+        }) as BoxedFilter
+    }
+}
+
+impl Filter for Contains {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        Value::from(match (value, &self.value) {
+            (Value::String(v), Value::String(substr)) => v.contains(substr.as_str()),
+            (Value::Array(values), _) => values.iter().any(|v| scalars_equal(v, &self.value)),
+            (Value::Object(map), Value::String(key)) => map.contains_key(key),
+            (Value::Null, _) => false,
+            _ => bail!("Invalid type"),
+        })
+    }
+}
+
+/// Selects a part of a value, `null` if nothing is found
+#[derive(Clone)]
+pub struct Select {
+    selector: JsonSelector,
+}
+
+impl Select {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let selector = match single_scalar_arg(args)? {
+            Value::String(path) => JsonSelector::new(&path)?,
+            _ => bail!("Path must be a string"),
+        };
+        Box::new(Self { selector }) as BoxedFilter
+    }
+}
+
+impl Filter for Select {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        self.selector.find(value).next()
+            .map(|v| v.value.clone())
+            .unwrap_or(Value::Null)
+    }
+}
+
+/// Integral numbers are kept as integers so they are not written with a fraction
+fn number_value(v: f64) -> Value {
+    if v.fract() == 0.0 && v.abs() < (1u64 << 53) as f64 {
+        Value::from(v as i64)
+    } else {
+        Value::from(v)
+    }
+}
 
-        Value::from(match (&self.value, value) {
-            (String(v1), String(v2)) if v1 == v2 => true,
-            (Number(v1), Number(v2)) if v1 == v2 => true,
-            (Bool(v1), Bool(v2)) if v1 == v2 => true,
-            (Null, Null) => true,
-            // TODO: Implement equality for arrays and objects
-            _ => false,
+/// Splits a string like `10.4gb` into the number and the rest of the string
+#[throws(AnyError)]
+fn split_number(s: &str) -> (f64, &str) {
+    let s = s.trim_start();
+    let end = s.char_indices()
+        .find(|&(ix, c)| {
+            !(c.is_ascii_digit() || c == '.' || (ix == 0 && (c == '-' || c == '+')))
         })
+        .map(|(ix, _)| ix)
+        .unwrap_or(s.len());
+    match s[..end].parse() {
+        Ok(number) => (number, s[end..].trim()),
+        Err(_) => bail!("Cannot parse number: {:?}", s),
+    }
+}
+
+/// Parses durations like `12ms`, `3.2d` or `1h30m` into seconds,
+/// numbers without a unit are in the unit from the arguments, seconds by default
+#[derive(Clone)]
+pub struct ParseDuration {
+    default_unit: f64,
+}
+
+impl ParseDuration {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let default_unit = match args {
+            Value::Null => 1.0,
+            Value::Array(seq) if seq.is_empty() => 1.0,
+            _ => match single_scalar_arg(args)? {
+                Value::String(unit) => match duration_unit(&unit) {
+                    Some(unit) => unit,
+                    None => bail!("Unknown duration unit: {:?}", unit),
+                },
+                _ => bail!("Unit must be a string"),
+            },
+        };
+        Box::new(Self { default_unit }) as BoxedFilter
+    }
+}
+
+fn duration_unit(unit: &str) -> Option<f64> {
+    Some(match unit {
+        "ns" | "nanos" => 1e-9,
+        "us" | "µs" | "micros" => 1e-6,
+        "ms" | "millis" => 1e-3,
+        "s" | "sec" | "" => 1.0,
+        "m" | "min" => 60.0,
+        "h" => 3600.0,
+        "d" => 86400.0,
+        "w" => 604800.0,
+        _ => return None,
+    })
+}
+
+impl Filter for ParseDuration {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let s = match value {
+            Value::Number(v) => return number_value(v.as_f64().unwrap() * self.default_unit),
+            Value::String(s) => s.trim(),
+            _ => bail!("Invalid type"),
+        };
+        let (number, unit) = split_number(s)?;
+        if unit.is_empty() {
+            return number_value(number * self.default_unit);
+        }
+        let mut seconds = 0.0;
+        let (mut number, mut rest) = (number, unit);
+        loop {
+            let unit_end = rest.find(|c: char| c.is_ascii_digit()).unwrap_or(rest.len());
+            match duration_unit(rest[..unit_end].trim()) {
+                Some(unit) => seconds += number * unit,
+                None => bail!("Unknown duration unit in {:?}", s),
+            }
+            rest = &rest[unit_end..];
+            if rest.is_empty() {
+                break;
+            }
+            let (next_number, next_rest) = split_number(rest)?;
+            number = next_number;
+            rest = next_rest;
+        }
+        number_value(seconds)
+    }
+}
+
+/// Parses sizes like `512b`, `10.4gb` or `1.5GiB` into bytes,
+/// multiples are powers of 1024 unless another base is passed
+#[derive(Clone)]
+pub struct ParseBytes {
+    base: f64,
+}
+
+impl ParseBytes {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let base = args_f64(args, &["base"])?[0].unwrap_or(1024.0);
+        Box::new(Self { base }) as BoxedFilter
+    }
+}
+
+impl Filter for ParseBytes {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let s = match value {
+            Value::Number(_) => return value.clone(),
+            Value::String(s) => s,
+            _ => bail!("Invalid type"),
+        };
+        let (number, unit) = split_number(s)?;
+        let unit = unit.to_ascii_lowercase();
+        let unit = unit.strip_suffix("ib")
+            .or_else(|| unit.strip_suffix('b'))
+            .unwrap_or(unit.as_str());
+        let power = match unit {
+            "" => 0,
+            "k" => 1,
+            "m" => 2,
+            "g" => 3,
+            "t" => 4,
+            "p" => 5,
+            _ => bail!("Unknown size unit in {:?}", s),
+        };
+        number_value(number * self.base.powi(power))
+    }
+}
+
+/// Parses percents like `73%` or `73` into a ratio
+#[derive(Clone)]
+pub struct ParsePercent;
+
+impl ParsePercent {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        check_no_args(args)?;
+        Box::new(Self) as BoxedFilter
+    }
+}
+
+impl Filter for ParsePercent {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let percent = match value {
+            Value::Number(v) => v.as_f64().unwrap(),
+            Value::String(s) => {
+                match split_number(s)? {
+                    (number, "%") | (number, "") => number,
+                    _ => bail!("Cannot parse percent: {:?}", s),
+                }
+            }
+            _ => bail!("Invalid type"),
+        };
+        Value::from(percent / 100.0)
+    }
+}
+
+fn parse_number(s: &str) -> Option<Value> {
+    let s = s.trim();
+    if let Ok(v) = s.parse::<i64>() {
+        Some(Value::from(v))
+    } else {
+        s.parse::<f64>().ok()
+            .filter(|v| v.is_finite())
+            .map(Value::from)
+    }
+}
+
+/// Converts strings and booleans into numbers
+#[derive(Clone)]
+pub struct ToNumber;
+
+impl ToNumber {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        check_no_args(args)?;
+        Box::new(Self) as BoxedFilter
+    }
+}
+
+impl Filter for ToNumber {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        match value {
+            Value::Number(_) => value.clone(),
+            Value::Bool(v) => Value::from(*v as u8),
+            Value::String(s) => {
+                match parse_number(s) {
+                    Some(v) => v,
+                    None => bail!("Cannot convert to a number: {:?}", s.trim()),
+                }
+            }
+            _ => bail!("Invalid type"),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum TimeFormat {
+    /// RFC3339 strings and numbers of seconds
+    Auto,
+    Rfc3339,
+    /// Number of seconds, milliseconds or microseconds since epoch
+    Epoch(f64),
+    Strftime(String),
+}
+
+/// Parses time into seconds since epoch
+#[derive(Clone)]
+pub struct ParseTime {
+    format: TimeFormat,
+}
+
+impl ParseTime {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let format = match args {
+            Value::Null => TimeFormat::Auto,
+            Value::Array(seq) if seq.is_empty() => TimeFormat::Auto,
+            _ => match single_scalar_arg(args)? {
+                Value::String(format) => match format.as_str() {
+                    "rfc3339" => TimeFormat::Rfc3339,
+                    "epoch_s" => TimeFormat::Epoch(1.0),
+                    "epoch_ms" => TimeFormat::Epoch(1e3),
+                    "epoch_us" => TimeFormat::Epoch(1e6),
+                    f if f.contains('%') => TimeFormat::Strftime(f.to_string()),
+                    _ => bail!("Unknown time format: {:?}", format),
+                },
+                _ => bail!("Time format must be a string"),
+            },
+        };
+        Box::new(Self { format }) as BoxedFilter
+    }
+}
+
+impl Filter for ParseTime {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        match (&self.format, value) {
+            (TimeFormat::Auto, Value::Number(_)) => value.clone(),
+            (TimeFormat::Epoch(divisor), Value::Number(v)) => {
+                number_value(v.as_f64().unwrap() / divisor)
+            }
+            (TimeFormat::Epoch(divisor), Value::String(s)) => {
+                match s.trim().parse::<f64>() {
+                    Ok(v) => number_value(v / divisor),
+                    Err(_) => bail!("Cannot parse timestamp: {:?}", s),
+                }
+            }
+            (TimeFormat::Auto, Value::String(s)) | (TimeFormat::Rfc3339, Value::String(s)) => {
+                match DateTime::parse_from_rfc3339(s.trim()) {
+                    Ok(dt) => timestamp_value(dt.timestamp(), dt.timestamp_subsec_nanos()),
+                    Err(e) => bail!("Cannot parse time {:?}: {}", s, e),
+                }
+            }
+            (TimeFormat::Strftime(format), Value::String(s)) => parse_strftime(s.trim(), format)?,
+            _ => bail!("Invalid type"),
+        }
+    }
+}
+
+/// Time without an offset is in UTC, a date without time is at midnight
+#[throws(AnyError)]
+fn parse_strftime(s: &str, format: &str) -> Value {
+    if let Ok(dt) = DateTime::parse_from_str(s, format) {
+        return timestamp_value(dt.timestamp(), dt.timestamp_subsec_nanos());
+    }
+    if let Ok(dt) = NaiveDateTime::parse_from_str(s, format) {
+        let dt = dt.and_utc();
+        return timestamp_value(dt.timestamp(), dt.timestamp_subsec_nanos());
+    }
+    match NaiveDate::parse_from_str(s, format) {
+        Ok(date) => timestamp_value(date.and_time(NaiveTime::MIN).and_utc().timestamp(), 0),
+        Err(e) => bail!("Cannot parse time {:?} with format {:?}: {}", s, format, e),
+    }
+}
+
+fn timestamp_value(seconds: i64, nanos: u32) -> Value {
+    if nanos == 0 {
+        Value::from(seconds)
+    } else {
+        Value::from(seconds as f64 + nanos as f64 / 1e9)
+    }
+}
+
+/// Number of seconds passed since a timestamp in seconds
+#[derive(Clone)]
+pub struct Age;
+
+impl Age {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        check_no_args(args)?;
+        Box::new(Self) as BoxedFilter
+    }
+}
+
+impl Filter for Age {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        match value {
+            Value::Number(v) => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+                Value::from(now - v.as_f64().unwrap())
+            }
+            _ => bail!("Invalid type"),
+        }
+    }
+}
+
+#[throws(AnyError)]
+fn regex_arg(arg: Option<&Value>) -> Regex {
+    match arg {
+        Some(Value::String(pattern)) => {
+            Regex::new(pattern).map_err(|e| anyhow!("Invalid regex {:?}: {}", pattern, e))?
+        }
+        Some(_) => bail!("Pattern must be a string"),
+        None => bail!("Pattern is required"),
+    }
+}
+
+/// Checks if a string matches the pattern, `null` does not match anything
+#[derive(Clone)]
+pub struct RegexMatch {
+    regex: Regex,
+}
+
+impl RegexMatch {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let args = named_args(args, &["pattern"])?;
+        Box::new(Self { regex: regex_arg(args[0])? }) as BoxedFilter
+    }
+}
+
+impl Filter for RegexMatch {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        match value {
+            Value::String(v) => Value::from(self.regex.is_match(v)),
+            Value::Null => Value::from(false),
+            _ => bail!("Invalid type"),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum CaptureGroup {
+    Index(usize),
+    Name(String),
+}
+
+/// Extracts a capture group, the first one by default, as a string or as a number
+/// when the `number` argument is set. Returns `null` when the pattern does not match
+#[derive(Clone)]
+pub struct RegexExtract {
+    regex: Regex,
+    group: CaptureGroup,
+    number: bool,
+}
+
+impl RegexExtract {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let args = named_args(args, &["pattern", "group", "number"])?;
+        let regex = regex_arg(args[0])?;
+        let group = match args[1] {
+            Some(Value::Number(ix)) => match ix.as_u64() {
+                Some(ix) if (ix as usize) < regex.captures_len() => CaptureGroup::Index(ix as usize),
+                _ => bail!("Invalid capture group: {}", ix),
+            },
+            Some(Value::String(name)) => {
+                if !regex.capture_names().any(|n| n == Some(name.as_str())) {
+                    bail!("Unknown capture group: {}", name);
+                }
+                CaptureGroup::Name(name.clone())
+            }
+            Some(group) => bail!("Invalid capture group: {}", group),
+            None if regex.captures_len() > 1 => CaptureGroup::Index(1),
+            None => CaptureGroup::Index(0),
+        };
+        let number = match args[2] {
+            Some(Value::Bool(number)) => *number,
+            Some(number) => bail!("Invalid number argument: {}", number),
+            None => false,
+        };
+        Box::new(Self { regex, group, number }) as BoxedFilter
+    }
+}
+
+impl Filter for RegexExtract {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let v = match value {
+            Value::String(v) => v,
+            Value::Null => return Value::Null,
+            _ => bail!("Invalid type"),
+        };
+        let captured = self.regex.captures(v).and_then(|captures| {
+            match &self.group {
+                CaptureGroup::Index(ix) => captures.get(*ix),
+                CaptureGroup::Name(name) => captures.name(name),
+            }
+        });
+        match captured {
+            Some(captured) if self.number => match parse_number(captured.as_str()) {
+                Some(number) => number,
+                None => bail!("Cannot parse number: {:?}", captured.as_str()),
+            },
+            Some(captured) => Value::from(captured.as_str()),
+            None => Value::Null,
+        }
+    }
+}
+
+/// Replaces all the matches, the replacement can refer to capture groups as `$1` or `${name}`
+#[derive(Clone)]
+pub struct RegexReplace {
+    regex: Regex,
+    replacement: String,
+}
+
+impl RegexReplace {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let args = named_args(args, &["pattern", "replacement"])?;
+        let replacement = match args[1] {
+            Some(Value::String(replacement)) => replacement.clone(),
+            Some(_) => bail!("Replacement must be a string"),
+            None => String::new(),
+        };
+        Box::new(Self { regex: regex_arg(args[0])?, replacement }) as BoxedFilter
+    }
+}
+
+impl Filter for RegexReplace {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        match value {
+            Value::String(v) => {
+                Value::from(self.regex.replace_all(v, self.replacement.as_str()).into_owned())
+            }
+            _ => bail!("Invalid type"),
+        }
+    }
+}
+
+/// Maps values by an object of pairs. Strings are compared with keys as is,
+/// other values are compared with keys parsed as numbers, booleans or `null`
+#[derive(Clone)]
+pub struct Map {
+    pairs: Vec<(String, Value, Value)>,
+    default: Option<Value>,
+}
+
+impl Map {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let (pairs, default) = match args {
+            Value::Object(map) => match map.get("values") {
+                Some(Value::Object(pairs)) => {
+                    if let Some(key) = map.keys().find(|key| *key != "values" && *key != "default") {
+                        bail!("Unknown argument: {}", key);
+                    }
+                    (pairs, map.get("default").cloned())
+                }
+                _ => (map, None),
+            },
+            _ => bail!("Mapping must be an object"),
+        };
+        let mut prepared_pairs = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let parsed_key = parse_number(key).unwrap_or_else(|| match key.as_str() {
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                "null" => Value::Null,
+                _ => Value::String(key.clone()),
+            });
+            prepared_pairs.push((key.clone(), parsed_key, single_scalar_arg(value)?));
+        }
+        if let Some(default) = &default {
+            single_scalar_arg(default)?;
+        }
+        Box::new(Self { pairs: prepared_pairs, default }) as BoxedFilter
+    }
+}
+
+impl Filter for Map {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let mapped = self.pairs.iter()
+            .find(|(key, parsed_key, _)| match value {
+                Value::String(v) => v == key,
+                v => scalars_equal(parsed_key, v),
+            })
+            .map(|(_, _, mapped)| mapped)
+            .or_else(|| self.default.as_ref());
+        match mapped {
+            Some(mapped) => mapped.clone(),
+            None => bail!("No mapping for {}", value),
+        }
+    }
+}
+
+#[throws(AnyError)]
+fn f64_value(value: &Value) -> f64 {
+    match value {
+        Value::Number(v) => v.as_f64().unwrap(),
+        _ => bail!("Invalid type"),
+    }
+}
+
+/// Numbers are compared with numbers and strings with strings
+#[throws(AnyError)]
+fn compare_scalars(v1: &Value, v2: &Value) -> Option<Ordering> {
+    match (v1, v2) {
+        (Value::Number(v1), Value::Number(v2)) => {
+            v1.as_f64().unwrap().partial_cmp(&v2.as_f64().unwrap())
+        }
+        (Value::String(v1), Value::String(v2)) => Some(v1.cmp(v2)),
+        (Value::Null, _) => None,
+        _ => bail!("Cannot compare {} with {}", v1, v2),
+    }
+}
+
+#[throws(AnyError)]
+fn comparable_arg(arg: Option<&Value>) -> Value {
+    match arg {
+        Some(arg @ Value::Number(_)) | Some(arg @ Value::String(_)) => arg.clone(),
+        Some(arg) => bail!("Invalid argument: {:?}", arg),
+        None => bail!("Argument required"),
+    }
+}
+
+#[derive(Clone)]
+pub struct NotEqual {
+    value: Value,
+}
+
+impl NotEqual {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        Box::new(Self {
+            value: single_scalar_arg(args)?
+        }) as BoxedFilter
+    }
+}
+
+impl Filter for NotEqual {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        Value::from(!scalars_equal(&self.value, value))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum CompareOp {
+    Greater,
+    GreaterOrEqual,
+    Less,
+    LessOrEqual,
+}
+
+/// Compares a value with the argument, `null` is never matched
+#[derive(Clone)]
+pub struct Compare {
+    op: CompareOp,
+    value: Value,
+}
+
+impl Compare {
+    #[throws(AnyError)]
+    fn create(args: &Value, op: CompareOp) -> BoxedFilter {
+        let args = named_args(args, &["value"])?;
+        Box::new(Self {
+            op,
+            value: comparable_arg(args[0])?,
+        }) as BoxedFilter
+    }
+
+    #[throws(AnyError)]
+    pub fn create_gt(args: &Value) -> BoxedFilter {
+        Self::create(args, CompareOp::Greater)?
+    }
+
+    #[throws(AnyError)]
+    pub fn create_ge(args: &Value) -> BoxedFilter {
+        Self::create(args, CompareOp::GreaterOrEqual)?
+    }
+
+    #[throws(AnyError)]
+    pub fn create_lt(args: &Value) -> BoxedFilter {
+        Self::create(args, CompareOp::Less)?
+    }
+
+    #[throws(AnyError)]
+    pub fn create_le(args: &Value) -> BoxedFilter {
+        Self::create(args, CompareOp::LessOrEqual)?
+    }
+}
+
+impl Filter for Compare {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        Value::from(match (self.op, compare_scalars(value, &self.value)?) {
+            (_, None) => false,
+            (CompareOp::Greater, Some(ord)) => ord == Ordering::Greater,
+            (CompareOp::GreaterOrEqual, Some(ord)) => ord != Ordering::Less,
+            (CompareOp::Less, Some(ord)) => ord == Ordering::Less,
+            (CompareOp::LessOrEqual, Some(ord)) => ord != Ordering::Greater,
+        })
+    }
+}
+
+/// Checks if a value is within the inclusive range
+#[derive(Clone)]
+pub struct Between {
+    min: Value,
+    max: Value,
+}
+
+impl Between {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let args = named_args(args, &["min", "max"])?;
+        Box::new(Self {
+            min: comparable_arg(args[0])?,
+            max: comparable_arg(args[1])?,
+        }) as BoxedFilter
+    }
+}
+
+impl Filter for Between {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let above_min = compare_scalars(value, &self.min)?.map_or(false, |ord| ord != Ordering::Less);
+        let below_max = compare_scalars(value, &self.max)?.map_or(false, |ord| ord != Ordering::Greater);
+        Value::from(above_min && below_max)
+    }
+}
+
+/// Checks if a value equals to any of the arguments
+#[derive(Clone)]
+pub struct In {
+    values: Vec<Value>,
+}
+
+impl In {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let values = match args {
+            Value::Array(values) if !values.is_empty() => values.clone(),
+            Value::Number(_) | Value::String(_) | Value::Bool(_) => vec!(args.clone()),
+            _ => bail!("List of values required"),
+        };
+        for value in &values {
+            single_scalar_arg(value)?;
+        }
+        Box::new(Self { values }) as BoxedFilter
+    }
+}
+
+impl Filter for In {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        Value::from(self.values.iter().any(|v| scalars_equal(v, value)))
+    }
+}
+
+#[derive(Clone)]
+pub struct Clamp {
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl Clamp {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let args = args_f64(args, &["min", "max"])?;
+        let (min, max) = (args[0], args[1]);
+        match (min, max) {
+            (None, None) => bail!("Min or max required"),
+            (Some(min), Some(max)) if min > max => bail!("Min is greater than max"),
+            _ => {}
+        }
+        Box::new(Self { min, max }) as BoxedFilter
+    }
+}
+
+impl Filter for Clamp {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let mut v = f64_value(value)?;
+        if let Some(min) = self.min {
+            v = v.max(min);
+        }
+        if let Some(max) = self.max {
+            v = v.min(max);
+        }
+        number_value(v)
+    }
+}
+
+#[derive(Clone)]
+pub struct Abs;
+
+impl Abs {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        check_no_args(args)?;
+        Box::new(Self) as BoxedFilter
+    }
+}
+
+impl Filter for Abs {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        number_value(f64_value(value)?.abs())
+    }
+}
+
+/// Rounds to the number of decimal digits, zero by default
+#[derive(Clone)]
+pub struct Round {
+    digits: i32,
+}
+
+impl Round {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let digits = args_f64(args, &["digits"])?[0].unwrap_or(0.0);
+        if digits.fract() != 0.0 || !(0.0..=MAX_ROUND_DIGITS as f64).contains(&digits) {
+            bail!("Number of digits must be an integer from 0 to {}: {}", MAX_ROUND_DIGITS, digits);
+        }
+        Box::new(Self { digits: digits as i32 }) as BoxedFilter
+    }
+}
+
+impl Filter for Round {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let v = f64_value(value)?;
+        if self.digits == 0 {
+            return number_value(v.round());
+        }
+        let factor = 10f64.powi(self.digits);
+        number_value((v * factor).round() / factor)
+    }
+}
+
+#[derive(Clone)]
+pub struct Floor;
+
+impl Floor {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        check_no_args(args)?;
+        Box::new(Self) as BoxedFilter
+    }
+}
+
+impl Filter for Floor {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        number_value(f64_value(value)?.floor())
+    }
+}
+
+#[derive(Clone)]
+pub struct Ceil;
+
+impl Ceil {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        check_no_args(args)?;
+        Box::new(Self) as BoxedFilter
+    }
+}
+
+impl Filter for Ceil {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        number_value(f64_value(value)?.ceil())
+    }
+}
+
+/// Logarithm with the base from the arguments, natural by default
+#[derive(Clone)]
+pub struct Log {
+    base: Option<f64>,
+}
+
+impl Log {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let base = args_f64(args, &["base"])?[0];
+        if let Some(base) = base {
+            if base <= 0.0 || base == 1.0 {
+                bail!("Invalid logarithm base: {}", base);
+            }
+        }
+        Box::new(Self { base }) as BoxedFilter
+    }
+}
+
+impl Filter for Log {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let v = f64_value(value)?;
+        if v <= 0.0 {
+            bail!("Logarithm of non-positive number: {}", v);
+        }
+        number_value(match self.base {
+            Some(base) => v.log(base),
+            None => v.ln(),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct Pow {
+    exponent: f64,
+}
+
+impl Pow {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        Box::new(Self {
+            exponent: single_arg_f64(args, "exponent")?
+        }) as BoxedFilter
+    }
+}
+
+impl Filter for Pow {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let v = f64_value(value)?.powf(self.exponent);
+        if !v.is_finite() {
+            bail!("Result is not a finite number");
+        }
+        number_value(v)
+    }
+}
+
+#[derive(Clone)]
+pub struct Add {
+    term: f64,
+}
+
+impl Add {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        Box::new(Self {
+            term: single_arg_f64(args, "value")?
+        }) as BoxedFilter
+    }
+
+    #[throws(AnyError)]
+    pub fn create_sub(args: &Value) -> BoxedFilter {
+        Box::new(Self {
+            term: -single_arg_f64(args, "value")?
+        }) as BoxedFilter
+    }
+}
+
+impl Filter for Add {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        number_value(f64_value(value)? + self.term)
+    }
+}
+
+#[throws(AnyError)]
+fn path_arg(arg: Option<&Value>) -> Option<JsonSelector> {
+    match arg {
+        Some(Value::String(path)) => Some(JsonSelector::new(path)?),
+        Some(_) => bail!("Path must be a string"),
+        None => None,
+    }
+}
+
+/// Elements of an array or values of an object, `null` has no elements
+#[throws(AnyError)]
+fn elements(value: &Value) -> Vec<&Value> {
+    match value {
+        Value::Array(values) => values.iter().collect(),
+        Value::Object(map) => map.values().collect(),
+        Value::Null => vec!(),
+        _ => bail!("Array or object required"),
+    }
+}
+
+/// Selects a sub-path of an element, `null` if nothing is found
+fn select<'a>(selector: &'a Option<JsonSelector>, element: &'a Value) -> &'a Value {
+    static NULL: Value = Value::Null;
+
+    match selector {
+        Some(selector) => selector.find(element).next().map_or(&NULL, |found| found.value),
+        None => element,
+    }
+}
+
+/// Number of elements of an array or an object, or length of a string
+#[derive(Clone)]
+pub struct Len;
+
+impl Len {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        check_no_args(args)?;
+        Box::new(Self) as BoxedFilter
+    }
+}
+
+impl Filter for Len {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        match value {
+            Value::String(v) => Value::from(v.chars().count()),
+            _ => Value::from(elements(value)?.len()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Aggregation {
+    Sum,
+    Min,
+    Max,
+    Avg,
+}
+
+/// Reduces numbers of an array or an object, missing and `null` values are skipped.
+/// A sum of no elements is zero and other aggregations are `null`
+#[derive(Clone)]
+pub struct Aggregate {
+    aggregation: Aggregation,
+    selector: Option<JsonSelector>,
+}
+
+impl Aggregate {
+    #[throws(AnyError)]
+    fn create(args: &Value, aggregation: Aggregation) -> BoxedFilter {
+        let args = named_args(args, &["path"])?;
+        Box::new(Self {
+            aggregation,
+            selector: path_arg(args[0])?,
+        }) as BoxedFilter
+    }
+
+    #[throws(AnyError)]
+    pub fn create_sum(args: &Value) -> BoxedFilter {
+        Self::create(args, Aggregation::Sum)?
+    }
+
+    #[throws(AnyError)]
+    pub fn create_min(args: &Value) -> BoxedFilter {
+        Self::create(args, Aggregation::Min)?
+    }
+
+    #[throws(AnyError)]
+    pub fn create_max(args: &Value) -> BoxedFilter {
+        Self::create(args, Aggregation::Max)?
+    }
+
+    #[throws(AnyError)]
+    pub fn create_avg(args: &Value) -> BoxedFilter {
+        Self::create(args, Aggregation::Avg)?
+    }
+}
+
+impl Filter for Aggregate {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let mut count = 0;
+        let mut sum = 0.0;
+        let mut extremum: Option<(f64, &Value)> = None;
+        for element in elements(value)? {
+            let element = match select(&self.selector, element) {
+                Value::Null => continue,
+                element => element,
+            };
+            let v = f64_value(element)?;
+            count += 1;
+            sum += v;
+            extremum = match (self.aggregation, extremum) {
+                (Aggregation::Min, Some((e, _))) if e <= v => extremum,
+                (Aggregation::Max, Some((e, _))) if e >= v => extremum,
+                _ => Some((v, element)),
+            };
+        }
+        match self.aggregation {
+            Aggregation::Sum => number_value(sum),
+            Aggregation::Avg if count > 0 => number_value(sum / count as f64),
+            Aggregation::Min | Aggregation::Max => {
+                extremum.map_or(Value::Null, |(_, element)| element.clone())
+            }
+            Aggregation::Avg => Value::Null,
+        }
+    }
+}
+
+/// Number of elements that are truthy or equal to the `value` argument
+#[derive(Clone)]
+pub struct CountWhere {
+    selector: Option<JsonSelector>,
+    value: Option<Value>,
+}
+
+impl CountWhere {
+    #[throws(AnyError)]
+    pub fn create(args: &Value) -> BoxedFilter {
+        let args = named_args(args, &["path", "value"])?;
+        Box::new(Self {
+            selector: path_arg(args[0])?,
+            value: args[1].map(single_scalar_arg).transpose()?,
+        }) as BoxedFilter
+    }
+}
+
+impl Filter for CountWhere {
+    #[throws(AnyError)]
+    fn apply(&self, value: &Value) -> Value {
+        let mut count = 0;
+        for element in elements(value)? {
+            let element = select(&self.selector, element);
+            let matched = match &self.value {
+                Some(expected) => scalars_equal(expected, element),
+                None => is_truthy(element),
+            };
+            if matched {
+                count += 1;
+            }
+        }
+        Value::from(count)
     }
 }
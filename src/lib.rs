@@ -2,13 +2,18 @@ pub mod config;
 pub mod convert;
 mod filters;
 pub mod prepare;
+pub mod self_metrics;
 pub mod service;
+mod tls;
 mod tmpl;
+pub mod web;
 
 use anyhow::{Error as AnyError};
 
 use fehler::throws;
 
+use flate2::Crc;
+
 use std::io::BufReader;
 use std::fs::File;
 use std::path::Path;
@@ -21,4 +26,12 @@ pub fn read_config(path: impl AsRef<Path>) -> Config {
         File::open(path)?
     );
     serde_yaml::from_reader(config_file)?
-}
\ No newline at end of file
+}
+
+/// Reads config updating the checksum with the config content
+#[throws(AnyError)]
+pub fn read_config_with_checksum(path: impl AsRef<Path>, checksum: &mut Crc) -> Config {
+    let content = std::fs::read(path)?;
+    checksum.update(&content);
+    serde_yaml::from_slice(&content)?
+}
@@ -1,35 +1,43 @@
 use actix_web::{
     web,
     App,
+    HttpResponse,
     HttpServer,
 };
+use actix_web::dev::Service;
+use actix_web::http::header;
 
-use anyhow::{bail, Context, Error as AnyError};
+use anyhow::{bail, Error as AnyError};
 
 use clap::Clap;
 
-use json_exporter::read_config;
-use json_exporter::convert::ResolvedMetric;
-use json_exporter::prepare::PreparedConfig;
+use futures::future::{ok, Either};
+
+use json_exporter::config::{Secret, Tls};
 use json_exporter::service::{
     AppState,
+    ConfigSource,
     info,
     metrics,
+    parse_base_url,
+    probe,
+    reload,
     resolve_global_labels,
+    self_metrics,
 };
+use json_exporter::web::{TlsServerConfig, WebConfig};
 
 use mimalloc::MiMalloc;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::num::NonZeroU8;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::time::delay_for;
 
-use url::Url;
-
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
@@ -41,10 +49,22 @@ struct Opts {
     host: String,
     #[clap(long, short='P', default_value="9114")]
     port: u16,
+    #[clap(long="web.config")]
+    web_config: Option<PathBuf>,
+    #[clap(long="web.tls-cert")]
+    web_tls_cert: Option<PathBuf>,
+    #[clap(long="web.tls-key")]
+    web_tls_key: Option<PathBuf>,
     #[clap(long)]
-    base_url: String,
+    base_url: Option<String>,
     #[clap(long, multiple=true, number_of_values=1)]
     endpoint_url: Vec<String>,
+    #[clap(long, multiple=true, number_of_values=1)]
+    module: Vec<String>,
+    #[clap(long, multiple=true, number_of_values=1)]
+    allowed_target: Vec<String>,
+    #[clap(long="probe.allow-any-target")]
+    probe_allow_any_target: bool,
     #[clap(long, default_value="5")]
     concurrency: NonZeroU8,
     #[clap(long, default_value="10000")]
@@ -53,6 +73,20 @@ struct Opts {
     cache_expiration_ms: u32,
     #[clap(long)]
     namespace: Option<String>,
+    #[clap(long)]
+    tls_ca_file: Option<PathBuf>,
+    #[clap(long)]
+    tls_cert_file: Option<PathBuf>,
+    #[clap(long)]
+    tls_key_file: Option<PathBuf>,
+    #[clap(long)]
+    tls_pkcs12_file: Option<PathBuf>,
+    #[clap(long)]
+    tls_pkcs12_password_file: Option<PathBuf>,
+    #[clap(long)]
+    tls_server_name: Option<String>,
+    #[clap(long)]
+    tls_insecure_skip_verify: bool,
     config: PathBuf,
 }
 
@@ -67,102 +101,210 @@ fn parse_endpoint_url(url_dsl: &str) -> Result<(String, String), AnyError> {
     })
 }
 
+fn parse_module(module_dsl: &str) -> Result<(String, PathBuf), AnyError> {
+    Ok(match &module_dsl.splitn(2, ':').collect::<Vec<_>>()[..] {
+        [""] => bail!("Missing module name"),
+        [_] => bail!("Missing module config path"),
+        [module_name, config_path] => {
+            (module_name.to_string(), PathBuf::from(config_path))
+        },
+        _ => unreachable!(),
+    })
+}
+
 #[actix_web::main]
 async fn main() -> Result<(), AnyError> {
     env_logger::init();
 
     let opts = Opts::parse();
 
-    let mut base_url = Url::parse(&opts.base_url)
-        .with_context(|| format!("Invalid url: {}", &opts.base_url))?;
-    if base_url.query().is_some() || base_url.fragment().is_some() {
-        bail!(
-            "Base url must not contain query or fragment parts: {}", &base_url
-        );
-    }
-    if !base_url.path().ends_with('/') {
-        let mut base_url_path_segments = match base_url.path_segments_mut() {
-            Ok(segments) => segments,
-            Err(()) => bail!("Not a base url"),
-        };
-        base_url_path_segments.push("");
-    }
-
     let endpoint_urls = opts.endpoint_url.iter()
         .map(String::as_str)
         .map(parse_endpoint_url)
         .collect::<Result<HashMap<_, _>, _>>()?;
+    let modules = opts.module.iter()
+        .map(String::as_str)
+        .map(parse_module)
+        .collect::<Result<Vec<_>, _>>()?;
+    let base_url = opts.base_url.as_deref()
+        .map(parse_base_url)
+        .transpose()?;
+    let allowed_targets = opts.allowed_target.iter()
+        .map(String::as_str)
+        .map(parse_base_url)
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut web_config = opts.web_config.as_deref()
+        .map(WebConfig::read)
+        .transpose()?
+        .unwrap_or_default();
+    match (&opts.web_tls_cert, &opts.web_tls_key) {
+        (Some(cert_file), Some(key_file)) => {
+            web_config.tls_server_config = Some(TlsServerConfig {
+                cert_file: cert_file.clone(),
+                key_file: key_file.clone(),
+            });
+        }
+        (None, None) => {}
+        _ => bail!("Both --web.tls-cert and --web.tls-key must be set"),
+    }
+    let web_tls_config = web_config.rustls_config()?;
+    let basic_auth = web_config.basic_auth()?;
     let timeout = Duration::from_millis(opts.timeout_ms as u64);
-    let config = read_config(&opts.config)?;
-    let prepared_config = PreparedConfig::create_from(
-        &config, &base_url, &endpoint_urls
-    )?;
-
+    let cache_expiration = Duration::from_millis(opts.cache_expiration_ms as u64);
 
-    for global_label in &prepared_config.global_labels {
-        log::info!("Global labels url: {}", &global_label.url);
+    let config_source = ConfigSource {
+        config_path: opts.config.clone(),
+        modules,
+        base_url,
+        endpoint_urls,
+        namespace: opts.namespace.clone(),
+        allowed_targets,
+        allow_any_target: opts.probe_allow_any_target,
+        tls: Tls {
+            ca_file: opts.tls_ca_file.clone(),
+            cert_file: opts.tls_cert_file.clone(),
+            key_file: opts.tls_key_file.clone(),
+            pkcs12_file: opts.tls_pkcs12_file.clone(),
+            pkcs12_password: opts.tls_pkcs12_password_file.clone()
+                .map(|file| Secret::File { file }),
+            server_name: opts.tls_server_name.clone(),
+            insecure_skip_verify: opts.tls_insecure_skip_verify,
+        },
+    };
+    let configs = config_source.read()?;
+    for (module_name, module_config_path) in &config_source.modules {
+        log::info!(
+            "Module [{}]: {}", module_name, module_config_path.display()
+        );
     }
-    for endpoint in &prepared_config.endpoints {
-        if let Some(endpoint_id) = &endpoint.id {
-            log::info!("Endpoint url [{}]: {}", endpoint_id, &endpoint.url);
-        } else {
-            log::info!("Endpoint url: {}", &endpoint.url);
+
+    let global_labels = match &configs.prepared_config {
+        Some(prepared_config) => {
+            for global_label in &prepared_config.global_labels {
+                log::info!("Global labels url: {}", &global_label.url);
+            }
+            for endpoint in &prepared_config.endpoints {
+                if let Some(endpoint_id) = &endpoint.id {
+                    log::info!("Endpoint url [{}]: {}", endpoint_id, &endpoint.url);
+                } else {
+                    log::info!("Endpoint url: {}", &endpoint.url);
+                }
+            }
+
+            loop {
+                match resolve_global_labels(prepared_config, timeout).await {
+                    Ok(labels) => {
+                        log::debug!("Global labels: {:?}", &labels);
+                        break labels;
+                    },
+                    Err(e) => {
+                        log::error!("Error when resolving global labels: {}", e);
+                        log::warn!(
+                            "Waiting {} seconds before retry",
+                            GLOBAL_LABELS_RETRY_INTERVAL_SECS
+                        );
+                        delay_for(
+                            Duration::from_secs(GLOBAL_LABELS_RETRY_INTERVAL_SECS)
+                        ).await;
+                        continue;
+                    }
+                }
+            }
         }
-    }
+        None => {
+            log::info!("Base url is not set, only /probe endpoint is available");
+            BTreeMap::new()
+        }
+    };
+
+    let app_state = AppState::new(
+        opts.concurrency.get(),
+        timeout,
+        cache_expiration,
+        config_source,
+        configs,
+        global_labels,
+    );
 
-    let client = reqwest::Client::new();
-    let app_state = loop {
-        // TODO: How we can rid of those clones?
-        let prepared_config = prepared_config.clone();
-        let base_url = base_url.clone();
-        match resolve_global_labels(&prepared_config, &client, timeout).await {
-            Ok(labels) => {
-                log::debug!("Global labels: {:?}", &labels);
-                let root_metric = ResolvedMetric::new_root(
-                    opts.namespace.unwrap_or_else(||
-                        config.namespace.clone().unwrap_or_else(|| "".to_string())
-                    ),
-                    labels,
-                );
-                break AppState::new(
-                    prepared_config,
-                    root_metric,
-                    client,
-                    base_url,
-                    opts.concurrency.get(),
-                    timeout,
-                    Duration::from_millis(opts.cache_expiration_ms as u64),
-                );
-            },
+    let reload_state = app_state.clone();
+    actix_web::rt::spawn(async move {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
             Err(e) => {
-                log::error!("Error when resolving global labels: {}", e);
-                log::warn!(
-                    "Waiting {} seconds before retry",
-                    GLOBAL_LABELS_RETRY_INTERVAL_SECS
-                );
-                delay_for(
-                    Duration::from_secs(GLOBAL_LABELS_RETRY_INTERVAL_SECS)
-                ).await;
-                continue;
+                log::error!("Cannot listen SIGHUP signal: {}", e);
+                return;
             }
+        };
+        while hangups.recv().await.is_some() {
+            log::info!("Received SIGHUP, reloading config");
+            // Errors are already logged
+            reload_state.reload().await.ok();
         }
-    };
+    });
     let app_state = Arc::new(Mutex::new(app_state));
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         // println!("Creating http application");
         let app_state = app_state.lock().expect("app state mutex lock");
+        let basic_auth = basic_auth.clone();
         App::new()
             .data((*app_state).clone())
+            .wrap_fn(move |req, srv| {
+                if basic_auth.is_authorized(req.headers()) {
+                    Either::Left(srv.call(req))
+                } else {
+                    Either::Right(ok(req.into_response(
+                        HttpResponse::Unauthorized()
+                            .header(header::WWW_AUTHENTICATE, "Basic")
+                            .finish()
+                    )))
+                }
+            })
             .route("/", web::get().to(info))
             .route("/metrics", web::get().to(metrics))
+            .route("/probe", web::get().to(probe))
+            .route("/self-metrics", web::get().to(self_metrics))
+            .route("/-/reload", web::post().to(reload))
     })
-    .workers(1)
     .max_connections(100)
-    .keep_alive(30)
-    .bind(format!("{}:{}", &opts.host, &opts.port))?
-    .run()
-    .await?;
+    .keep_alive(30);
+    let addr = format!("{}:{}", &opts.host, &opts.port);
+    let server = match web_tls_config {
+        Some(web_tls_config) => server.bind_rustls(addr, web_tls_config)?,
+        None => server.bind(addr)?,
+    };
+    server.run().await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{parse_endpoint_url, parse_module};
+
+    #[test]
+    fn test_parse_endpoint_url() {
+        assert_eq!(
+            parse_endpoint_url("nodes:http://es:9200/_nodes/stats").unwrap(),
+            ("nodes".to_string(), "http://es:9200/_nodes/stats".to_string())
+        );
+        assert!(parse_endpoint_url("").is_err());
+        assert!(parse_endpoint_url("nodes").is_err());
+    }
+
+    #[test]
+    fn test_parse_module() {
+        assert_eq!(
+            parse_module("kafka:kafka_manager.yaml").unwrap(),
+            ("kafka".to_string(), PathBuf::from("kafka_manager.yaml"))
+        );
+        assert_eq!(
+            parse_module("kafka:/etc/json-exporter/kafka:manager.yaml").unwrap(),
+            ("kafka".to_string(), PathBuf::from("/etc/json-exporter/kafka:manager.yaml"))
+        );
+        assert!(parse_module("").is_err());
+        assert!(parse_module("kafka").is_err());
+    }
+}
@@ -1,9 +1,13 @@
-use anyhow::{anyhow, bail, Error as AnyhowError};
+use anyhow::{anyhow, bail, Context, Error as AnyhowError};
 
 use fehler::{throw, throws};
 
 use jsonpath::{Selector, Match, Step};
 
+use regex::Regex;
+
+use reqwest::header::{HeaderName, HeaderValue};
+
 use serde_json::Value;
 
 use std::collections::HashMap;
@@ -12,19 +16,27 @@ use std::convert::TryFrom;
 use url::Url;
 
 use crate::config::{
+    Auth,
+    Condition,
     Config,
+    DuplicateSeriesPolicy,
     Endpoint,
     Filter,
     GlobalLabels,
+    InvalidNames,
     Label,
     Metric,
     MetricType,
+    Secret,
+    Timestamp,
     UrlParts
 };
+use crate::convert::{is_metric_name_char, is_valid_label_name, is_valid_metric_name};
 use crate::filters::{
     self,
-    Filter as PreparedFilter,
+    BoxedFilter,
 };
+use crate::tls::create_client;
 use crate::tmpl::{
     string_with_placeholders,
     Placeholder,
@@ -35,6 +47,10 @@ use crate::tmpl::{
 #[derive(Clone)]
 pub struct PreparedConfig {
     pub namespace: Option<String>,
+    pub client: reqwest::Client,
+    pub duplicate_series: DuplicateSeriesPolicy,
+    pub invalid_names: InvalidNames,
+    pub metric_type_overrides: MetricTypeOverrides,
     pub global_labels: Vec<PreparedGlobalLabels>,
     pub endpoints: Vec<PreparedEndpoint>,
 }
@@ -46,9 +62,17 @@ impl PreparedConfig {
         base_url: &Url,
         override_endpoint_urls: &HashMap<String, String>,
     ) -> Self {
+        if let Some(namespace) = &config.namespace {
+            if !namespace.is_empty() && !is_valid_metric_name(namespace) {
+                bail!("Invalid namespace: {:?}", namespace);
+            }
+        }
+        let auth = config.auth.as_ref().map(PreparedAuth::create_from).transpose()?;
         let mut prepared_global_labels = vec!();
         for global_labels in &config.global_labels {
-            prepared_global_labels.push(PreparedGlobalLabels::create_from(global_labels, base_url)?);
+            prepared_global_labels.push(
+                PreparedGlobalLabels::create_from(global_labels, base_url, auth.as_ref())?
+            );
         }
         let mut prepared_endpoints = vec!();
         for endpoint in &config.endpoints {
@@ -58,41 +82,160 @@ impl PreparedConfig {
             });
             prepared_endpoints.push(
                 PreparedEndpoint::create_from(
-                    endpoint, base_url, override_endpoint_url
+                    endpoint, base_url, override_endpoint_url, auth.as_ref()
                 )?
             );
         }
         Self {
             namespace: config.namespace.clone(),
+            client: create_client(config.tls.as_ref())?,
+            duplicate_series: config.duplicate_series,
+            invalid_names: config.invalid_names,
+            metric_type_overrides: MetricTypeOverrides::create_from(
+                &config.metric_type_overrides, config.namespace.as_deref()
+            )?,
             global_labels: prepared_global_labels,
             endpoints: prepared_endpoints,
         }
     }
 }
 
+/// Metric types that take precedence over inferred and inherited ones
+#[derive(Clone, Default)]
+pub struct MetricTypeOverrides {
+    pub(crate) namespace_prefix: Option<String>,
+    pub(crate) names: HashMap<String, MetricType>,
+    pub(crate) patterns: Vec<(Regex, MetricType)>,
+}
+
+impl MetricTypeOverrides {
+    /// Types in the order their regular expressions are checked
+    const TYPES: [MetricType; 3] = [MetricType::Gauge, MetricType::Counter, MetricType::Untyped];
+
+    /// Patterns that consist of valid metric name characters are exact names,
+    /// others are regular expressions matching the whole name
+    #[throws(AnyhowError)]
+    pub(crate) fn create_from(
+        overrides: &HashMap<MetricType, Vec<String>>,
+        namespace: Option<&str>,
+    ) -> Self {
+        if let Some(metric_type) = overrides.keys().find(|t| !Self::TYPES.contains(*t)) {
+            bail!("Cannot override metric type with: {:?}", metric_type);
+        }
+        let mut names = HashMap::new();
+        let mut patterns = vec!();
+        for &metric_type in Self::TYPES.iter() {
+            let metric_patterns = match overrides.get(&metric_type) {
+                Some(metric_patterns) => metric_patterns,
+                None => continue,
+            };
+            for pattern in metric_patterns {
+                if pattern.chars().all(is_metric_name_char) {
+                    match names.insert(pattern.clone(), metric_type) {
+                        Some(other_type) if other_type != metric_type => bail!(
+                            "Metric type of {} is overridden with both {:?} and {:?}",
+                            pattern, other_type, metric_type
+                        ),
+                        _ => {}
+                    }
+                } else {
+                    let regex = Regex::new(&format!("^(?:{})$", pattern))
+                        .with_context(|| format!("Invalid metric name pattern: {}", pattern))?;
+                    patterns.push((regex, metric_type));
+                }
+            }
+        }
+        Self {
+            namespace_prefix: namespace.filter(|ns| !ns.is_empty()).map(|ns| format!("{}_", ns)),
+            names,
+            patterns,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct PreparedGlobalLabels {
     pub url: Url,
+    pub auth: Option<PreparedAuth>,
     pub labels: PreparedLabels,
 }
 
 impl PreparedGlobalLabels {
     #[throws(AnyhowError)]
-    fn create_from(global_labels: &GlobalLabels, base_url: &Url) -> Self {
+    fn create_from(
+        global_labels: &GlobalLabels,
+        base_url: &Url,
+        auth: Option<&PreparedAuth>,
+    ) -> Self {
         let mut url_patch = UrlPatch::default();
         url_patch.add_path_with_query(&global_labels.url);
         let url = url_patch.apply(&base_url)?;
         Self {
             url,
+            auth: auth.cloned(),
             labels: PreparedLabels::try_from(&global_labels.labels)?,
         }
     }
 }
 
-#[derive(Clone)]
+/// Credentials with all the secrets already read
+#[derive(Clone, Default)]
+pub struct PreparedAuth {
+    pub basic: Option<(String, Option<String>)>,
+    pub bearer: Option<String>,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl PreparedAuth {
+    #[throws(AnyhowError)]
+    fn create_from(auth: &Auth) -> Self {
+        let basic = match &auth.basic {
+            Some(basic) => Some((
+                basic.username.clone(),
+                basic.password.as_ref().map(Secret::resolve).transpose()?,
+            )),
+            None => None,
+        };
+        let mut headers = vec!();
+        for (name, value) in &auth.headers {
+            let header_name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("Invalid header name: {}", name))?;
+            let mut header_value = HeaderValue::from_str(&value.resolve()?)
+                .with_context(|| format!("Invalid value of header: {}", name))?;
+            header_value.set_sensitive(true);
+            headers.push((header_name, header_value));
+        }
+        Self {
+            basic,
+            bearer: auth.bearer.as_ref().map(Secret::resolve).transpose()?,
+            headers,
+        }
+    }
+}
+
+impl Secret {
+    #[throws(AnyhowError)]
+    pub(crate) fn resolve(&self) -> String {
+        match self {
+            Secret::Value(value) => value.clone(),
+            Secret::File { file } => {
+                std::fs::read_to_string(file)
+                    .with_context(|| format!("Cannot read secret file: {}", file.display()))?
+                    .trim_end_matches(&['\n', '\r'][..])
+                    .to_string()
+            }
+            Secret::Env { env } => {
+                std::env::var(env)
+                    .with_context(|| format!("Cannot read secret from environment: {}", env))?
+            }
+        }
+    }
+}
+
 pub struct PreparedLabel {
     pub name: String,
     pub value_processor: TemplateProcessor,
+    pub filters: Vec<BoxedFilter>,
 }
 
 impl<'a> TryFrom<&'a Label> for PreparedLabel {
@@ -100,9 +243,29 @@ impl<'a> TryFrom<&'a Label> for PreparedLabel {
 
     #[throws(AnyhowError)]
     fn try_from(label: &Label) -> Self {
+        if !is_valid_label_name(&label.name) {
+            bail!("Invalid label name: {:?}", &label.name);
+        }
+        let mut prepared_filters = vec!();
+        for filter in &label.modifiers {
+            prepared_filters.push(filter.prepare()?);
+        }
         Self {
             name: label.name.clone(),
             value_processor: TemplateProcessor::create_from(&label.value)?,
+            filters: prepared_filters,
+        }
+    }
+}
+
+impl Clone for PreparedLabel {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            value_processor: self.value_processor.clone(),
+            filters: self.filters.iter()
+                .map(|f| dyn_clone::clone_box(f.as_ref()))
+                .collect(),
         }
     }
 }
@@ -129,7 +292,9 @@ impl<'a> TryFrom<&'a Vec<Label>> for PreparedLabels {
 pub struct PreparedEndpoint {
     pub id: Option<String>,
     pub url: Url,
+    pub auth: Option<PreparedAuth>,
     pub name: String,
+    pub condition: Option<PreparedCondition>,
     pub metrics: PreparedMetrics,
 }
 
@@ -139,6 +304,7 @@ impl PreparedEndpoint {
         endpoint: &Endpoint,
         base_url: &Url,
         overriden_endpoint_url: Option<&String>,
+        global_auth: Option<&PreparedAuth>,
     ) -> Self {
         let mut url_patch = UrlPatch::default();
         url_patch.add_endpoint_url(&endpoint.url, &endpoint.url_parts, true)?;
@@ -146,11 +312,17 @@ impl PreparedEndpoint {
             url_patch.add_endpoint_url(&overriden_endpoint_url, &endpoint.url_parts, false)?;
         }
         let url = url_patch.apply(&base_url)?;
+        let auth = match &endpoint.auth {
+            Some(auth) => Some(PreparedAuth::create_from(auth)?),
+            None => global_auth.cloned(),
+        };
         Self {
             id: endpoint.id.clone(),
             url,
+            auth,
             name: endpoint.name.clone(),
-            metrics: PreparedMetrics::create_from(&endpoint.metrics, None)?
+            condition: endpoint.when.as_ref().map(PreparedCondition::create_from).transpose()?,
+            metrics: PreparedMetrics::create_from(&endpoint.metrics, None, None, None)?
         }
     }
 }
@@ -162,11 +334,15 @@ impl PreparedMetrics {
     #[throws(AnyhowError)]
     pub fn create_from(
         metrics: &[Metric],
-        metric_type: Option<MetricType>
+        metric_type: Option<MetricType>,
+        unit: Option<&str>,
+        parent_path: Option<&str>,
     ) -> Self {
         let mut prepared_metrics = vec!();
         for metric in metrics.iter() {
-            prepared_metrics.push(PreparedMetric::create_from(metric, metric_type)?);
+            prepared_metrics.push(
+                PreparedMetric::create_from(metric, metric_type, unit, parent_path)?
+            );
         }
         Self(prepared_metrics)
     }
@@ -177,12 +353,21 @@ impl PreparedMetrics {
 }
 
 pub struct PreparedMetric {
+    /// Full path of the metric in the config, used in warnings
+    pub config_path: String,
     pub selector: JsonSelector,
     pub metric_type: Option<MetricType>,
+    pub unit: Option<String>,
     pub name: Option<String>,
     pub name_processor: Option<TemplateProcessor>,
-    pub filters: Vec<Box<dyn PreparedFilter + Send>>,
+    pub help_processor: Option<TemplateProcessor>,
+    pub filters: Vec<BoxedFilter>,
     pub labels: PreparedLabels,
+    pub condition: Option<PreparedCondition>,
+    pub distribution: Option<PreparedDistribution>,
+    pub states: Vec<String>,
+    pub value_label: Option<String>,
+    pub timestamp: Option<PreparedTimestamp>,
     pub metrics: PreparedMetrics,
 }
 
@@ -191,12 +376,29 @@ impl PreparedMetric {
     fn create_from(
         metric: &Metric,
         parent_metric_type: Option<MetricType>,
+        parent_unit: Option<&str>,
+        parent_path: Option<&str>,
     ) -> Self {
+        let config_path = match parent_path {
+            Some(parent_path) if metric.path.is_empty() => parent_path.to_string(),
+            Some(parent_path) if !parent_path.is_empty() => {
+                format!("{}.{}", parent_path, &metric.path)
+            }
+            _ => metric.path.clone(),
+        };
         let metric_type = metric.metric_type.or(parent_metric_type);
-        // TODO: validate metric and label names
+        let unit = metric.unit.as_deref().or(parent_unit);
+        // Names with placeholders are sanitized when resolving them
+        if let Some(name) = &metric.name {
+            if !name.contains('$') && !name.chars().all(is_metric_name_char) {
+                bail!("Invalid metric name at [{}]: {:?}", &config_path, name);
+            }
+        }
         let name = metric.name.clone();
         let name_processor = metric.name.as_ref().map(|n| TemplateProcessor::create_from(n))
             .transpose()?;
+        let help_processor = metric.help.as_ref().map(|h| TemplateProcessor::create_from(h))
+            .transpose()?;
         let selector = JsonSelector::new(&metric.path)?;
 
         let mut prepared_filters = vec!();
@@ -204,14 +406,36 @@ impl PreparedMetric {
             prepared_filters.push(filter.prepare()?);
         }
 
+        let distribution = PreparedDistribution::create_from(metric, metric_type, &config_path)?;
+        if !metric.states.is_empty() && metric_type != Some(MetricType::StateSet) {
+            bail!("Only stateset can have states at [{}]", &config_path);
+        }
+        if let Some(value_label) = &metric.value_label {
+            if !is_valid_label_name(value_label) {
+                bail!("Invalid label name at [{}]: {:?}", &config_path, value_label);
+            }
+        }
+
+        let metrics = PreparedMetrics::create_from(
+            &metric.metrics, metric_type, unit, Some(&config_path)
+        )?;
+
         Self {
+            config_path,
             metric_type,
+            unit: unit.map(str::to_string),
             name,
             name_processor,
+            help_processor,
             selector,
             filters: prepared_filters,
             labels: PreparedLabels::try_from(&metric.labels)?,
-            metrics: PreparedMetrics::create_from(&metric.metrics, metric_type)?,
+            condition: metric.when.as_ref().map(PreparedCondition::create_from).transpose()?,
+            distribution,
+            states: metric.states.clone(),
+            value_label: metric.value_label.clone(),
+            timestamp: metric.timestamp.as_ref().map(PreparedTimestamp::create_from).transpose()?,
+            metrics,
         }
     }
 }
@@ -219,19 +443,140 @@ impl PreparedMetric {
 impl Clone for PreparedMetric {
     fn clone(&self) -> Self {
         Self {
+            config_path: self.config_path.clone(),
             selector: self.selector.clone(),
             metric_type: self.metric_type,
+            unit: self.unit.clone(),
             name: self.name.clone(),
             name_processor: self.name_processor.clone(),
+            help_processor: self.help_processor.clone(),
             filters: self.filters.iter()
                 .map(|f| dyn_clone::clone_box(f.as_ref()))
                 .collect(),
             labels: self.labels.clone(),
+            condition: self.condition.clone(),
+            distribution: self.distribution.clone(),
+            states: self.states.clone(),
+            value_label: self.value_label.clone(),
+            timestamp: self.timestamp.clone(),
             metrics: self.metrics.clone(),
         }
     }
 }
 
+/// Sub-paths of a histogram or a summary relative to the metric path
+#[derive(Clone)]
+pub struct PreparedDistribution {
+    /// Buckets of a histogram or quantiles of a summary
+    pub points: JsonSelector,
+    pub percentiles: Option<bool>,
+    pub sum: Option<JsonSelector>,
+    pub count: Option<JsonSelector>,
+}
+
+impl PreparedDistribution {
+    #[throws(AnyhowError)]
+    fn create_from(
+        metric: &Metric, metric_type: Option<MetricType>, config_path: &str
+    ) -> Option<Self> {
+        let has_paths = metric.buckets.is_some() || metric.quantiles.is_some() ||
+            metric.sum.is_some() || metric.count.is_some() || metric.percentiles.is_some();
+        if !metric.metrics.is_empty() {
+            // Nested metrics can be histograms or summaries
+            if has_paths {
+                bail!("Metric with nested metrics cannot have buckets or quantiles at [{}]", config_path);
+            }
+            return None;
+        }
+        let points = match (metric_type, &metric.buckets, &metric.quantiles) {
+            (Some(MetricType::Histogram), Some(_), None) if metric.percentiles.is_some() => {
+                bail!("Only summary can have percentiles at [{}]", config_path)
+            }
+            (Some(MetricType::Histogram), Some(buckets), None) => buckets,
+            (Some(MetricType::Summary), None, Some(quantiles)) => quantiles,
+            (Some(MetricType::Histogram), _, _) => {
+                bail!("Histogram must have only buckets path at [{}]", config_path)
+            }
+            (Some(MetricType::Summary), _, _) => {
+                bail!("Summary must have only quantiles path at [{}]", config_path)
+            }
+            _ if has_paths => {
+                bail!(
+                    "Only histogram and summary can have buckets, quantiles, sum or count at [{}]",
+                    config_path
+                )
+            }
+            _ => return None,
+        };
+        Some(Self {
+            points: JsonSelector::new(points)?,
+            percentiles: metric.percentiles,
+            sum: metric.sum.as_deref().map(JsonSelector::new).transpose()?,
+            count: metric.count.as_deref().map(JsonSelector::new).transpose()?,
+        })
+    }
+}
+
+pub struct PreparedCondition {
+    pub selector: JsonSelector,
+    pub filters: Vec<BoxedFilter>,
+}
+
+impl PreparedCondition {
+    #[throws(AnyhowError)]
+    fn create_from(condition: &Condition) -> Self {
+        let mut prepared_filters = vec!();
+        for filter in &condition.modifiers {
+            prepared_filters.push(filter.prepare()?);
+        }
+        Self {
+            selector: JsonSelector::new(&condition.path)?,
+            filters: prepared_filters,
+        }
+    }
+}
+
+impl Clone for PreparedCondition {
+    fn clone(&self) -> Self {
+        Self {
+            selector: self.selector.clone(),
+            filters: self.filters.iter()
+                .map(|f| dyn_clone::clone_box(f.as_ref()))
+                .collect(),
+        }
+    }
+}
+
+pub struct PreparedTimestamp {
+    pub selector: JsonSelector,
+    pub filters: Vec<BoxedFilter>,
+}
+
+impl PreparedTimestamp {
+    #[throws(AnyhowError)]
+    fn create_from(timestamp: &Timestamp) -> Self {
+        let mut prepared_filters = vec!();
+        for filter in &timestamp.modifiers {
+            prepared_filters.push(filter.prepare()?);
+        }
+        Self {
+            selector: JsonSelector::new(&timestamp.path)?,
+            filters: prepared_filters,
+        }
+    }
+}
+
+impl Clone for PreparedTimestamp {
+    fn clone(&self) -> Self {
+        Self {
+            selector: self.selector.clone(),
+            filters: self.filters.iter()
+                .map(|f| dyn_clone::clone_box(f.as_ref()))
+                .collect(),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct JsonSelector {
     pub expression: String,
@@ -240,7 +585,7 @@ pub struct JsonSelector {
 
 impl JsonSelector {
     #[throws(AnyhowError)]
-    fn new(expression: &str) -> Self {
+    pub(crate) fn new(expression: &str) -> Self {
         let expression = if expression.is_empty() {
             "$".to_string()
         } else if expression.starts_with('$') {
@@ -266,12 +611,49 @@ impl JsonSelector {
 
 impl Filter {
     #[throws(AnyhowError)]
-    fn prepare(&self) -> Box<dyn PreparedFilter + Send> {
+    fn prepare(&self) -> BoxedFilter {
         let create_filter = match self.name.as_str() {
             "mul" | "multiply" => filters::Multiply::create,
             "div" | "divide" => filters::Divide::create,
+            "add" => filters::Add::create,
+            "sub" | "subtract" => filters::Add::create_sub,
+            "abs" => filters::Abs::create,
+            "round" => filters::Round::create,
+            "floor" => filters::Floor::create,
+            "ceil" => filters::Ceil::create,
+            "clamp" => filters::Clamp::create,
+            "log" => filters::Log::create,
+            "pow" => filters::Pow::create,
             "const" => filters::Const::create,
             "eq" | "equal" => filters::Equal::create,
+            "ne" | "not_equal" => filters::NotEqual::create,
+            "gt" => filters::Compare::create_gt,
+            "ge" => filters::Compare::create_ge,
+            "lt" => filters::Compare::create_lt,
+            "le" => filters::Compare::create_le,
+            "between" => filters::Between::create,
+            "in" => filters::In::create,
+            "bool" => filters::Bool::create,
+            "not" => filters::Not::create,
+            "exists" => filters::Exists::create,
+            "contains" => filters::Contains::create,
+            "select" => filters::Select::create,
+            "parse_duration" => filters::ParseDuration::create,
+            "parse_bytes" => filters::ParseBytes::create,
+            "parse_percent" => filters::ParsePercent::create,
+            "to_number" => filters::ToNumber::create,
+            "parse_time" => filters::ParseTime::create,
+            "age" => filters::Age::create,
+            "regex_match" => filters::RegexMatch::create,
+            "regex_extract" => filters::RegexExtract::create,
+            "regex_replace" => filters::RegexReplace::create,
+            "map" => filters::Map::create,
+            "len" => filters::Len::create,
+            "sum" => filters::Aggregate::create_sum,
+            "min" => filters::Aggregate::create_min,
+            "max" => filters::Aggregate::create_max,
+            "avg" => filters::Aggregate::create_avg,
+            "count_where" => filters::CountWhere::create,
             _ => throw!(anyhow!("Unknown filter: {}", &self.name)),
         };
         create_filter(&self.args)?
@@ -300,6 +682,20 @@ impl TemplateProcessor {
         }
     }
 
+    /// Returns the json value as is when the template consists of a single selector,
+    /// `null` is returned if nothing is found
+    #[throws(AnyhowError)]
+    pub fn apply_value(&self, found: &Match) -> Value {
+        match self.tmpl.as_slice() {
+            [PreparedPlaceholder::VarIdent(selector)] => {
+                selector.find(found.value).next()
+                    .map(|v| v.value.clone())
+                    .unwrap_or(Value::Null)
+            }
+            _ => Value::String(self.apply(found)?),
+        }
+    }
+
     #[throws(AnyhowError)]
     pub fn apply(&self, found: &Match) -> String {
         use PreparedPlaceholder::*;
@@ -503,8 +899,9 @@ impl PathDsl {
 
 #[cfg(test)]
 mod tests {
-    use super::{PathDsl, UrlPatch};
-    use crate::config::{UrlParts, QueryParam};
+    use super::{PathDsl, PreparedAuth, PreparedMetrics, UrlPatch};
+    use crate::config::{Auth, Metrics, UrlParts, QueryParam};
+    use indoc::indoc;
     use url::Url;
     use nom::lib::std::collections::HashMap;
 
@@ -732,4 +1129,82 @@ mod tests {
             "http://example.com/test/_local?"
         );
     }
+
+    #[test]
+    fn test_prepare_auth() {
+        let secret_path = std::env::temp_dir().join("json-exporter-test-api-key");
+        std::fs::write(&secret_path, "ApiKey c2VjcmV0\n").unwrap();
+        std::env::set_var("JSON_EXPORTER_TEST_PASSWORD", "passw0rd");
+        let auth: Auth = serde_yaml::from_str(&format!(
+            indoc! {"
+                basic:
+                  username: exporter
+                  password:
+                    env: JSON_EXPORTER_TEST_PASSWORD
+                headers:
+                  Authorization:
+                    file: {}
+            "},
+            secret_path.display()
+        )).unwrap();
+        let prepared_auth = PreparedAuth::create_from(&auth).unwrap();
+        assert_eq!(
+            prepared_auth.basic,
+            Some(("exporter".to_string(), Some("passw0rd".to_string())))
+        );
+        assert_eq!(prepared_auth.bearer, None);
+        assert_eq!(prepared_auth.headers.len(), 1);
+        assert_eq!(prepared_auth.headers[0].0, "authorization");
+        assert_eq!(prepared_auth.headers[0].1, "ApiKey c2VjcmV0");
+
+        let auth: Auth = serde_yaml::from_str(indoc! {"
+            bearer:
+              env: JSON_EXPORTER_TEST_MISSING_TOKEN
+        "}).unwrap();
+        assert_eq!(
+            PreparedAuth::create_from(&auth).err().unwrap().to_string(),
+            "Cannot read secret from environment: JSON_EXPORTER_TEST_MISSING_TOKEN"
+        );
+    }
+
+    #[test]
+    fn test_validate_names() {
+        let prepare = |config: &str| {
+            let metrics: Metrics = serde_yaml::from_str(config).unwrap();
+            PreparedMetrics::create_from(&metrics.metrics, None, None, None)
+                .err()
+                .map(|e| e.to_string())
+        };
+        assert_eq!(
+            prepare(indoc! {"
+                metrics:
+                - path: indices.*
+                  name: index_${1}
+                  labels:
+                  - name: index
+                    value: $1
+            "}),
+            None
+        );
+        assert_eq!(
+            prepare(indoc! {"
+                metrics:
+                - path: indices
+                  metrics:
+                  - path: docs.count
+                    name: docs.count
+            "}).as_deref(),
+            Some(r#"Invalid metric name at [indices.docs.count]: "docs.count""#)
+        );
+        assert_eq!(
+            prepare(indoc! {"
+                metrics:
+                - path: indices
+                  labels:
+                  - name: index-name
+                    value: test
+            "}).as_deref(),
+            Some(r#"Invalid label name: "index-name""#)
+        );
+    }
 }
@@ -0,0 +1,297 @@
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+use std::io::{Write as IOWrite};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::config::MetricType;
+use crate::convert::{escape_label_value, Format, ResolvedMetric, WarningKind};
+
+const SELF_METRICS_NAMESPACE: &str = "json_exporter";
+
+/// Exporter's own metrics accumulated over all scrapes
+#[derive(Default)]
+pub struct SelfMetrics {
+    config: Mutex<ConfigStats>,
+    targets: Mutex<BTreeMap<String, TargetStats>>,
+}
+
+#[derive(Default)]
+struct ConfigStats {
+    checksum: u32,
+    last_reload_successful: bool,
+    reloads_success: u64,
+    reloads_failure: u64,
+}
+
+#[derive(Default)]
+struct TargetStats {
+    scrapes: u64,
+    cache_hits: u64,
+    cache_misses: u64,
+    endpoints: BTreeMap<String, EndpointStats>,
+}
+
+#[derive(Default)]
+struct EndpointStats {
+    request_duration: Duration,
+    parsing_duration: Duration,
+    processing_duration: Duration,
+    series: usize,
+    warnings: BTreeMap<WarningKind, u64>,
+}
+
+/// Statistics of a single endpoint collected during one scrape
+pub struct EndpointScrapeStats {
+    pub endpoint: String,
+    pub request_duration: Duration,
+    pub parsing_duration: Duration,
+    pub processing_duration: Duration,
+    pub series: usize,
+    pub warnings: Vec<WarningKind>,
+}
+
+impl SelfMetrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets checksum of the initially loaded config
+    pub fn observe_config(&self, checksum: u32) {
+        let mut config = self.config.lock().expect("self metrics mutex lock");
+        config.checksum = checksum;
+        config.last_reload_successful = true;
+    }
+
+    /// Records a result of the config reload, checksum is `None` when the reload failed
+    pub fn observe_reload(&self, checksum: Option<u32>) {
+        let mut config = self.config.lock().expect("self metrics mutex lock");
+        match checksum {
+            Some(checksum) => {
+                config.checksum = checksum;
+                config.last_reload_successful = true;
+                config.reloads_success += 1;
+            }
+            None => {
+                config.last_reload_successful = false;
+                config.reloads_failure += 1;
+            }
+        }
+    }
+
+    pub fn observe_cache(&self, target: &str, hit: bool) {
+        let mut targets = self.targets.lock().expect("self metrics mutex lock");
+        let target_stats = targets.entry(target.to_string()).or_default();
+        if hit {
+            target_stats.cache_hits += 1;
+        } else {
+            target_stats.cache_misses += 1;
+        }
+    }
+
+    pub fn observe_scrape(&self, target: &str, endpoints: Vec<EndpointScrapeStats>) {
+        let mut targets = self.targets.lock().expect("self metrics mutex lock");
+        let target_stats = targets.entry(target.to_string()).or_default();
+        target_stats.scrapes += 1;
+        for scrape_stats in endpoints {
+            let endpoint_stats = target_stats.endpoints.entry(scrape_stats.endpoint)
+                .or_default();
+            endpoint_stats.request_duration += scrape_stats.request_duration;
+            endpoint_stats.parsing_duration += scrape_stats.parsing_duration;
+            endpoint_stats.processing_duration += scrape_stats.processing_duration;
+            endpoint_stats.series = scrape_stats.series;
+            for warning_kind in scrape_stats.warnings {
+                *endpoint_stats.warnings.entry(warning_kind).or_default() += 1;
+            }
+        }
+    }
+
+    /// Forgets statistics of the targets that are not probed anymore
+    pub fn retain_targets(&self, is_live: impl Fn(&str) -> bool) {
+        let mut targets = self.targets.lock().expect("self metrics mutex lock");
+        targets.retain(|target, _| is_live(target));
+    }
+
+    pub fn dump<W: IOWrite>(&self, format: Format, buf: &mut W) {
+        use MetricType::*;
+
+        {
+            let config = self.config.lock().expect("self metrics mutex lock");
+            self_metric("config_hash", Gauge, &[])
+                .dump(&Value::from(config.checksum), None, format, buf);
+            self_metric("config_last_reload_successful", Gauge, &[])
+                .dump(&Value::from(config.last_reload_successful), None, format, buf);
+            let reloads_metric_type = self_metric(
+                "config_reloads_total", Counter, &[("result", "success")]
+            )
+                .dump(&Value::from(config.reloads_success), None, format, buf);
+            self_metric("config_reloads_total", Counter, &[("result", "failure")])
+                .dump(&Value::from(config.reloads_failure), reloads_metric_type, format, buf);
+        }
+
+        let targets = self.targets.lock().expect("self metrics mutex lock");
+
+        dump_target_family(&targets, "scrapes_total", |t| t.scrapes, format, buf);
+        dump_target_family(&targets, "cache_hits_total", |t| t.cache_hits, format, buf);
+        dump_target_family(&targets, "cache_misses_total", |t| t.cache_misses, format, buf);
+
+        dump_endpoint_family(
+            &targets, "endpoint_request_duration_seconds_total", Counter,
+            |e| Value::from(e.request_duration.as_secs_f64()), format, buf,
+        );
+        dump_endpoint_family(
+            &targets, "endpoint_parsing_duration_seconds_total", Counter,
+            |e| Value::from(e.parsing_duration.as_secs_f64()), format, buf,
+        );
+        dump_endpoint_family(
+            &targets, "endpoint_processing_duration_seconds_total", Counter,
+            |e| Value::from(e.processing_duration.as_secs_f64()), format, buf,
+        );
+        dump_endpoint_family(
+            &targets, "endpoint_series", Gauge,
+            |e| Value::from(e.series), format, buf,
+        );
+
+        let mut seen_metric_type = None;
+        for (target, target_stats) in targets.iter() {
+            for (endpoint, endpoint_stats) in target_stats.endpoints.iter() {
+                for warning_kind in WarningKind::ALL.iter() {
+                    let count = endpoint_stats.warnings.get(warning_kind)
+                        .copied()
+                        .unwrap_or_default();
+                    seen_metric_type = self_metric(
+                        "endpoint_warnings_total",
+                        Counter,
+                        &[
+                            ("target", target),
+                            ("endpoint", endpoint),
+                            ("kind", warning_kind.as_str()),
+                        ]
+                    )
+                        .dump(&Value::from(count), seen_metric_type, format, buf);
+                }
+            }
+        }
+    }
+}
+
+fn dump_target_family<W: IOWrite>(
+    targets: &BTreeMap<String, TargetStats>,
+    name: &str,
+    get_value: impl Fn(&TargetStats) -> u64,
+    format: Format,
+    buf: &mut W,
+) {
+    let mut seen_metric_type = None;
+    for (target, target_stats) in targets.iter() {
+        seen_metric_type = self_metric(name, MetricType::Counter, &[("target", target)])
+            .dump(&Value::from(get_value(target_stats)), seen_metric_type, format, buf);
+    }
+}
+
+fn dump_endpoint_family<W: IOWrite>(
+    targets: &BTreeMap<String, TargetStats>,
+    name: &str,
+    metric_type: MetricType,
+    get_value: impl Fn(&EndpointStats) -> Value,
+    format: Format,
+    buf: &mut W,
+) {
+    let mut seen_metric_type = None;
+    for (target, target_stats) in targets.iter() {
+        for (endpoint, endpoint_stats) in target_stats.endpoints.iter() {
+            seen_metric_type = self_metric(
+                name, metric_type, &[("target", target), ("endpoint", endpoint)]
+            )
+                .dump(&get_value(endpoint_stats), seen_metric_type, format, buf);
+        }
+    }
+}
+
+fn self_metric(name: &str, metric_type: MetricType, labels: &[(&str, &str)]) -> ResolvedMetric {
+    ResolvedMetric {
+        name: format!("{}_{}", SELF_METRICS_NAMESPACE, name),
+        metric_type: Some(metric_type),
+        unit: None,
+        help: None,
+        timestamp: None,
+        labels: labels.iter()
+            .map(|(label_name, label_value)| {
+                (label_name.to_string(), escape_label_value(label_value.to_string()))
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EndpointScrapeStats, SelfMetrics};
+    use crate::convert::{Format, WarningKind};
+
+    use indoc::indoc;
+
+    use std::time::Duration;
+
+    #[test]
+    fn test_dump_self_metrics() {
+        let self_metrics = SelfMetrics::new();
+        self_metrics.observe_config(0x1234);
+        self_metrics.observe_reload(None);
+        self_metrics.observe_cache("http://es:9200/", false);
+        self_metrics.observe_cache("http://es:9200/", true);
+        self_metrics.observe_cache("http://es:9200/", true);
+        for _ in 0..2 {
+            self_metrics.observe_scrape(
+                "http://es:9200/",
+                vec!(
+                    EndpointScrapeStats {
+                        endpoint: "nodes".to_string(),
+                        request_duration: Duration::from_millis(250),
+                        parsing_duration: Duration::from_millis(5),
+                        processing_duration: Duration::from_millis(2),
+                        series: 42,
+                        warnings: vec!(WarningKind::Filter, WarningKind::Filter),
+                    },
+                )
+            );
+        }
+
+        self_metrics.observe_cache("http://es-old:9200/", false);
+        self_metrics.retain_targets(|target| target == "http://es:9200/");
+
+        let mut buf = vec!();
+        self_metrics.dump(Format::Prometheus, &mut buf);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            indoc! {r#"
+                # TYPE json_exporter_config_hash gauge
+                json_exporter_config_hash 4660
+                # TYPE json_exporter_config_last_reload_successful gauge
+                json_exporter_config_last_reload_successful 0
+                # TYPE json_exporter_config_reloads_total counter
+                json_exporter_config_reloads_total{result="success"} 0
+                json_exporter_config_reloads_total{result="failure"} 1
+                # TYPE json_exporter_scrapes_total counter
+                json_exporter_scrapes_total{target="http://es:9200/"} 2
+                # TYPE json_exporter_cache_hits_total counter
+                json_exporter_cache_hits_total{target="http://es:9200/"} 2
+                # TYPE json_exporter_cache_misses_total counter
+                json_exporter_cache_misses_total{target="http://es:9200/"} 1
+                # TYPE json_exporter_endpoint_request_duration_seconds_total counter
+                json_exporter_endpoint_request_duration_seconds_total{endpoint="nodes",target="http://es:9200/"} 0.5
+                # TYPE json_exporter_endpoint_parsing_duration_seconds_total counter
+                json_exporter_endpoint_parsing_duration_seconds_total{endpoint="nodes",target="http://es:9200/"} 0.01
+                # TYPE json_exporter_endpoint_processing_duration_seconds_total counter
+                json_exporter_endpoint_processing_duration_seconds_total{endpoint="nodes",target="http://es:9200/"} 0.004
+                # TYPE json_exporter_endpoint_series gauge
+                json_exporter_endpoint_series{endpoint="nodes",target="http://es:9200/"} 42
+                # TYPE json_exporter_endpoint_warnings_total counter
+                json_exporter_endpoint_warnings_total{endpoint="nodes",kind="template",target="http://es:9200/"} 0
+                json_exporter_endpoint_warnings_total{endpoint="nodes",kind="filter",target="http://es:9200/"} 4
+                json_exporter_endpoint_warnings_total{endpoint="nodes",kind="dump",target="http://es:9200/"} 0
+                json_exporter_endpoint_warnings_total{endpoint="nodes",kind="duplicate",target="http://es:9200/"} 0
+            "#}
+        );
+    }
+}
@@ -1,26 +1,34 @@
 use actix_web::{
     http,
     web,
+    HttpRequest,
     HttpResponse,
     Responder,
     ResponseError,
 };
 use actix_web::dev::HttpResponseBuilder;
-use actix_web::http::{header, ContentEncoding};
+use actix_web::http::header;
+use actix_web::web::Bytes;
 
-use anyhow::{Error as AnyError};
+use anyhow::{bail, Context, Error as AnyError};
 
-use flate2::Compression;
-use flate2::write::GzEncoder;
+use fehler::throws;
 
-use futures::future::try_join_all;
+use flate2::{Compression, Crc};
+use flate2::write::{GzEncoder, ZlibEncoder};
 
-use futures_locks::{RwLock as AsyncRwLock};
+use futures::future::join_all;
+
+use futures_locks::{Mutex as AsyncMutex, RwLock as AsyncRwLock};
 
 use jsonpath::{Match, Step};
 
-use std::collections::BTreeMap;
-use std::sync::Arc;
+use serde::Deserialize;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
 use tokio::time::timeout_at;
@@ -28,10 +36,24 @@ use tokio::sync::Semaphore;
 
 use url::Url;
 
-use crate::prepare::PreparedConfig;
-use crate::convert::ResolvedMetric;
+use crate::config::{Config, Tls};
+use crate::read_config_with_checksum;
+use crate::prepare::{PreparedAuth, PreparedConfig};
+use crate::convert::{
+    add_endpoints_status,
+    EndpointStatus,
+    Format,
+    MetricFamilies,
+    ResolvedMetric,
+};
+use crate::self_metrics::{EndpointScrapeStats, SelfMetrics};
 
-const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+const OPENMETRICS_MEDIA_TYPE: &str = "application/openmetrics-text";
+const CHECK_CONFIG_URL: &str = "http://localhost/";
+/// Probed targets are forgotten when they are not probed for this time
+const PROBE_TARGET_TTL: Duration = Duration::from_secs(600);
+/// Least recently probed targets are forgotten when there are more targets
+const MAX_PROBE_TARGETS: usize = 1000;
 
 #[derive(thiserror::Error, Debug)]
 pub enum ProcessMetricsError {
@@ -66,64 +88,515 @@ impl ResponseError for ProcessMetricsError {
     }
 }
 
+#[derive(thiserror::Error, Debug)]
+pub enum ProbeError {
+    #[error("unknown module: {0}")]
+    UnknownModule(String),
+    #[error("invalid target: {0}")]
+    InvalidTarget(AnyError),
+    #[error("target is not allowed: {0}")]
+    TargetNotAllowed(String),
+    #[error("error when preparing config: {0}")]
+    PrepareConfig(AnyError),
+    #[error("error when resolving global labels: {0}")]
+    GlobalLabels(AnyError),
+}
+
+impl ResponseError for ProbeError {
+    fn error_response(&self) -> HttpResponse {
+        HttpResponseBuilder::new(self.status_code())
+            .body(format!("{}", self))
+    }
+    fn status_code(&self) -> http::StatusCode {
+        use ProbeError::*;
+
+        match self {
+            UnknownModule(_) | InvalidTarget(_) => http::StatusCode::BAD_REQUEST,
+            TargetNotAllowed(_) => http::StatusCode::FORBIDDEN,
+            GlobalLabels(_) => http::StatusCode::BAD_GATEWAY,
+            PrepareConfig(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Where configs are read from, so they can be read again on reload
+pub struct ConfigSource {
+    pub config_path: PathBuf,
+    pub modules: Vec<(String, PathBuf)>,
+    pub base_url: Option<Url>,
+    pub endpoint_urls: HashMap<String, String>,
+    pub namespace: Option<String>,
+    /// Targets that can be probed
+    pub allowed_targets: Vec<Url>,
+    /// Allows probing any target with a module without credentials
+    /// when there are no allowed targets
+    pub allow_any_target: bool,
+    /// TLS options that override ones from the configs
+    pub tls: Tls,
+}
+
+/// Configs that were read and validated
+pub struct Configs {
+    pub checksum: u32,
+    pub config: Config,
+    pub modules: HashMap<String, Config>,
+    /// Config prepared for the base url, it is absent when base url is not set
+    pub prepared_config: Option<PreparedConfig>,
+}
+
+impl ConfigSource {
+    #[throws(AnyError)]
+    pub fn read(&self) -> Configs {
+        // Module configs are prepared for every probed target, so check them
+        // using a fake url to find out errors as early as possible
+        let check_url = Url::parse(CHECK_CONFIG_URL)?;
+
+        let mut checksum = Crc::new();
+        let mut config = read_config_with_checksum(&self.config_path, &mut checksum)
+            .with_context(|| format!(
+                "Cannot read config: {}", self.config_path.display()
+            ))?;
+        self.override_tls(&mut config);
+        let prepared_config = PreparedConfig::create_from(
+            &config,
+            self.base_url.as_ref().unwrap_or(&check_url),
+            &self.endpoint_urls,
+        )?;
+        let mut modules = HashMap::new();
+        for (module_name, module_config_path) in &self.modules {
+            let mut module_config = read_config_with_checksum(
+                module_config_path, &mut checksum
+            )
+                .with_context(|| format!(
+                    "Cannot read module config: {}", module_config_path.display()
+                ))?;
+            self.override_tls(&mut module_config);
+            PreparedConfig::create_from(
+                &module_config, &check_url, &self.endpoint_urls
+            )
+                .with_context(|| format!("Invalid module config: {}", module_name))?;
+            modules.insert(module_name.clone(), module_config);
+        }
+
+        Configs {
+            checksum: checksum.sum(),
+            config,
+            modules,
+            prepared_config: self.base_url.as_ref().map(|_| prepared_config),
+        }
+    }
+
+    fn override_tls(&self, config: &mut Config) {
+        config.tls = Some(
+            config.tls.take().unwrap_or_default().merge(&self.tls)
+        );
+    }
+
+    fn create_loaded_state(
+        &self,
+        configs: Configs,
+        global_labels: BTreeMap<String, String>,
+        concurrency: u8,
+        cache_expiration: Duration,
+    ) -> LoadedState {
+        let Configs { config, modules, prepared_config, .. } = configs;
+        let target = match (&self.base_url, prepared_config) {
+            (Some(base_url), Some(prepared_config)) => {
+                let root_metric = ResolvedMetric::new_root(
+                    self.namespace.clone()
+                        .or_else(|| config.namespace.clone())
+                        .unwrap_or_default(),
+                    global_labels,
+                );
+                Some(TargetState::new(
+                    base_url.to_string(),
+                    prepared_config,
+                    root_metric,
+                    concurrency,
+                    cache_expiration,
+                ))
+            }
+            _ => None,
+        };
+        LoadedState {
+            target,
+            probe: ProbeTargets::new(
+                self.namespace.clone(),
+                self.endpoint_urls.clone(),
+                self.allowed_targets.iter().cloned().collect(),
+                self.allow_any_target,
+                config,
+                modules,
+            ),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
-    base_url: Url,
-    client: reqwest::Client,
     concurrency: u8,
     timeout: Duration,
-    config: PreparedConfig,
-    root_metric: ResolvedMetric,
-    cache: Arc<AsyncRwLock<CachedMetrics>>,
+    cache_expiration: Duration,
+    source: Arc<ConfigSource>,
+    loaded: Arc<RwLock<Arc<LoadedState>>>,
+    reload_lock: Arc<AsyncMutex<()>>,
+    self_metrics: Arc<SelfMetrics>,
+}
+
+/// State that is replaced on every config reload
+struct LoadedState {
+    target: Option<TargetState>,
+    probe: ProbeTargets,
 }
 
 impl AppState {
     pub fn new(
-        config: PreparedConfig,
-        root_metric: ResolvedMetric,
-        client: reqwest::Client,
-        base_url: Url,
         concurrency: u8,
         timeout: Duration,
         cache_expiration: Duration,
+        source: ConfigSource,
+        configs: Configs,
+        global_labels: BTreeMap<String, String>,
     ) -> Self {
+        let self_metrics = SelfMetrics::new();
+        self_metrics.observe_config(configs.checksum);
+        let loaded = source.create_loaded_state(
+            configs, global_labels, concurrency, cache_expiration
+        );
         AppState {
-            base_url,
-            client,
             concurrency,
             timeout,
+            cache_expiration,
+            source: Arc::new(source),
+            loaded: Arc::new(RwLock::new(Arc::new(loaded))),
+            reload_lock: Arc::new(AsyncMutex::new(())),
+            self_metrics: Arc::new(self_metrics),
+        }
+    }
+
+    fn loaded(&self) -> Arc<LoadedState> {
+        self.loaded.read().expect("loaded state lock").clone()
+    }
+
+    /// Reads configs again and replaces current state. The current state
+    /// is kept when any of the configs is invalid
+    pub async fn reload(&self) -> Result<u32, AnyError> {
+        let _reload_guard = self.reload_lock.lock().await;
+        match self.read_loaded_state().await {
+            Ok((loaded, checksum)) => {
+                // Probed targets are forgotten with the old state
+                let base_target = loaded.target.as_ref().map(|target| target.name.clone());
+                self.self_metrics.retain_targets(|target| {
+                    base_target.as_deref() == Some(target)
+                });
+                *self.loaded.write().expect("loaded state lock") = Arc::new(loaded);
+                self.self_metrics.observe_reload(Some(checksum));
+                log::info!("Config was reloaded, checksum: {:08x}", checksum);
+                Ok(checksum)
+            }
+            Err(e) => {
+                self.self_metrics.observe_reload(None);
+                log::error!("Error when reloading config: {:#}", e);
+                Err(e)
+            }
+        }
+    }
+
+    async fn read_loaded_state(&self) -> Result<(LoadedState, u32), AnyError> {
+        let configs = self.source.read()?;
+        let checksum = configs.checksum;
+        let global_labels = match &configs.prepared_config {
+            Some(prepared_config) => {
+                resolve_global_labels(prepared_config, self.timeout).await
+                    .context("Error when resolving global labels")?
+            }
+            None => BTreeMap::new(),
+        };
+        let loaded = self.source.create_loaded_state(
+            configs, global_labels, self.concurrency, self.cache_expiration
+        );
+        Ok((loaded, checksum))
+    }
+}
+
+/// Everything that is needed to scrape a single backend
+#[derive(Clone)]
+pub struct TargetState {
+    name: String,
+    config: PreparedConfig,
+    root_metric: ResolvedMetric,
+    semaphore: Arc<Semaphore>,
+    cache: Arc<HashMap<Format, AsyncRwLock<CachedMetrics>>>,
+}
+
+impl TargetState {
+    fn new(
+        name: String,
+        config: PreparedConfig,
+        root_metric: ResolvedMetric,
+        concurrency: u8,
+        cache_expiration: Duration,
+    ) -> Self {
+        TargetState {
+            name,
             config,
             root_metric,
-            cache: Arc::new(AsyncRwLock::new(
-                CachedMetrics::new(cache_expiration)
-            )),
+            semaphore: Arc::new(Semaphore::new(concurrency as usize)),
+            cache: Arc::new(
+                Format::ALL.iter()
+                    .map(|&format| {
+                        (format, AsyncRwLock::new(CachedMetrics::new(format, cache_expiration)))
+                    })
+                    .collect()
+            ),
+        }
+    }
+
+    fn cache(&self, format: Format) -> &AsyncRwLock<CachedMetrics> {
+        self.cache.get(&format).expect("cache for every format")
+    }
+}
+
+/// Configs available for the `/probe` endpoint and the targets
+/// that were already probed
+#[derive(Clone)]
+pub struct ProbeTargets {
+    namespace: Option<String>,
+    endpoint_urls: Arc<HashMap<String, String>>,
+    allowed_targets: Arc<HashSet<Url>>,
+    allow_any_target: bool,
+    default_module: Arc<Config>,
+    modules: Arc<HashMap<String, Arc<Config>>>,
+    targets: Arc<AsyncRwLock<HashMap<TargetKey, ProbeTarget>>>,
+}
+
+type TargetKey = (Option<String>, Url);
+
+struct ProbeTarget {
+    state: TargetState,
+    last_probed: Mutex<Instant>,
+}
+
+impl ProbeTarget {
+    fn new(state: TargetState) -> Self {
+        ProbeTarget {
+            state,
+            last_probed: Mutex::new(Instant::now()),
         }
     }
+
+    fn last_probed(&self) -> Instant {
+        *self.last_probed.lock().expect("last probed lock")
+    }
+
+    fn touch(&self) {
+        *self.last_probed.lock().expect("last probed lock") = Instant::now();
+    }
+}
+
+impl ProbeTargets {
+    fn new(
+        namespace: Option<String>,
+        endpoint_urls: HashMap<String, String>,
+        allowed_targets: HashSet<Url>,
+        allow_any_target: bool,
+        default_module: Config,
+        modules: HashMap<String, Config>,
+    ) -> Self {
+        ProbeTargets {
+            namespace,
+            endpoint_urls: Arc::new(endpoint_urls),
+            allowed_targets: Arc::new(allowed_targets),
+            allow_any_target,
+            default_module: Arc::new(default_module),
+            modules: Arc::new(
+                modules.into_iter()
+                    .map(|(name, config)| (name, Arc::new(config)))
+                    .collect()
+            ),
+            targets: Arc::new(AsyncRwLock::new(HashMap::new())),
+        }
+    }
+
+    fn module(&self, module: Option<&str>) -> Result<&Config, ProbeError> {
+        match module {
+            Some(module) => self.modules.get(module)
+                .map(Arc::as_ref)
+                .ok_or_else(|| ProbeError::UnknownModule(module.to_string())),
+            None => Ok(&self.default_module),
+        }
+    }
+
+    /// Only listed targets can be probed unless any target is allowed explicitly,
+    /// credentials of a module are never sent to an arbitrary target
+    fn is_allowed(&self, config: &Config, base_url: &Url) -> bool {
+        if self.allowed_targets.is_empty() {
+            self.allow_any_target && !config.has_credentials()
+        } else {
+            self.allowed_targets.contains(base_url)
+        }
+    }
+
+    async fn target(
+        &self, state: &AppState, module: Option<&str>, target: &str
+    ) -> Result<TargetState, ProbeError> {
+        let config = self.module(module)?;
+        let base_url = parse_base_url(target)
+            .map_err(ProbeError::InvalidTarget)?;
+        if !self.is_allowed(config, &base_url) {
+            return Err(ProbeError::TargetNotAllowed(base_url.to_string()));
+        }
+        let target_key = (module.map(str::to_string), base_url);
+        if let Some(probe_target) = self.targets.read().await.get(&target_key) {
+            probe_target.touch();
+            return Ok(probe_target.state.clone());
+        }
+
+        let prepared_config = PreparedConfig::create_from(
+            config, &target_key.1, &self.endpoint_urls
+        )
+            .map_err(ProbeError::PrepareConfig)?;
+        // Do not remember the target until global labels are resolved
+        // so the next probe will try again
+        let labels = resolve_global_labels(&prepared_config, state.timeout).await
+            .map_err(ProbeError::GlobalLabels)?;
+        log::debug!("Global labels for {}: {:?}", &target_key.1, &labels);
+        let root_metric = ResolvedMetric::new_root(
+            self.namespace.clone()
+                .or_else(|| config.namespace.clone())
+                .unwrap_or_default(),
+            labels,
+        );
+        let target_state = TargetState::new(
+            target_key.1.to_string(),
+            prepared_config,
+            root_metric,
+            state.concurrency,
+            state.cache_expiration,
+        );
+
+        let mut targets = self.targets.write().await;
+        if !targets.contains_key(&target_key) &&
+            evict_probe_targets(&mut targets, Instant::now())
+        {
+            let loaded = state.loaded();
+            let mut live_targets = targets.values()
+                .map(|probe_target| probe_target.state.name.as_str())
+                .chain(loaded.target.as_ref().map(|target| target.name.as_str()))
+                .collect::<HashSet<_>>();
+            live_targets.insert(target_state.name.as_str());
+            state.self_metrics.retain_targets(|target| live_targets.contains(target));
+        }
+        let probe_target = targets.entry(target_key)
+            .or_insert_with(|| ProbeTarget::new(target_state));
+        probe_target.touch();
+        Ok(probe_target.state.clone())
+    }
+}
+
+/// Forgets expired targets and makes room for a new one,
+/// returns `true` when any target was forgotten
+fn evict_probe_targets(targets: &mut HashMap<TargetKey, ProbeTarget>, now: Instant) -> bool {
+    let num_targets = targets.len();
+    targets.retain(|_, probe_target| {
+        now.saturating_duration_since(probe_target.last_probed()) < PROBE_TARGET_TTL
+    });
+    while targets.len() >= MAX_PROBE_TARGETS {
+        let oldest_key = targets.iter()
+            .min_by_key(|(_, probe_target)| probe_target.last_probed())
+            .map(|(target_key, _)| target_key.clone());
+        match oldest_key {
+            Some(target_key) => {
+                log::debug!("Forgetting probe target: {}", &target_key.1);
+                targets.remove(&target_key);
+            }
+            None => break,
+        }
+    }
+    targets.len() < num_targets
+}
+
+#[derive(Deserialize)]
+pub struct ProbeParams {
+    target: String,
+    module: Option<String>,
+}
+
+/// Content codings the metrics can be compressed with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Encoding {
+    Identity,
+    Gzip,
+    Deflate,
+    Zstd,
+}
+
+impl Encoding {
+    /// In the order of preference when the client accepts several encodings
+    /// with the same quality
+    const ALL: [Encoding; 4] = [
+        Encoding::Zstd, Encoding::Gzip, Encoding::Deflate, Encoding::Identity
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+            Encoding::Zstd => "zstd",
+        }
+    }
+
+    fn encode(self, data: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+        Ok(match self {
+            Encoding::Identity => data.to_vec(),
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(vec!(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+            Encoding::Deflate => {
+                let mut encoder = ZlibEncoder::new(vec!(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+            Encoding::Zstd => zstd::encode_all(data, 0)?,
+        })
+    }
 }
 
 struct CachedMetrics {
+    format: Format,
     expiration_time: Duration,
     expired_at: Instant,
+    /// Uncompressed metrics
     buf: Vec<u8>,
+    /// Response bodies that are encoded on demand
+    bodies: Mutex<HashMap<Encoding, Bytes>>,
     err: Option<ProcessMetricsError>,
 }
 
 impl CachedMetrics {
-    fn new(cache_expiration: Duration) -> Self {
+    fn new(format: Format, cache_expiration: Duration) -> Self {
         Self {
+            format,
             expiration_time: cache_expiration,
             expired_at: Instant::now(),
             buf: vec!(),
+            bodies: Mutex::new(HashMap::new()),
             err: Some(ProcessMetricsError::CacheNotInitialized),
         }
     }
+
     fn set_ok(&mut self) {
         self.expired_at = Instant::now() + self.expiration_time;
+        self.bodies.get_mut().expect("cached bodies lock").clear();
         self.err = None;
     }
 
     fn set_error(&mut self, err: ProcessMetricsError) {
         self.expired_at = Instant::now() + self.expiration_time;
+        self.bodies.get_mut().expect("cached bodies lock").clear();
         self.err = Some(err);
     }
 
@@ -135,21 +608,58 @@ impl CachedMetrics {
         }
     }
 
-    fn to_response(&self) -> HttpResponse {
+    fn body(&self, encoding: Encoding) -> Result<Bytes, std::io::Error> {
+        let mut bodies = self.bodies.lock().expect("cached bodies lock");
+        if let Some(body) = bodies.get(&encoding) {
+            return Ok(body.clone());
+        }
+        let body = Bytes::from(encoding.encode(&self.buf)?);
+        bodies.insert(encoding, body.clone());
+        Ok(body)
+    }
+
+    fn to_response(&self, encoding: Encoding) -> HttpResponse {
         match &self.err {
-            None => prometheus_response(self.buf.clone()),
+            None => match self.body(encoding) {
+                Ok(body) => metrics_response(self.format, encoding, body),
+                Err(e) => ProcessMetricsError::IO(e).error_response(),
+            },
             Some(err) => err.error_response(),
         }
     }
 }
 
+/// Parses a url that is used as a base for all the config urls
+#[throws(AnyError)]
+pub fn parse_base_url(url: &str) -> Url {
+    let mut base_url = if url.contains("://") {
+        Url::parse(url)
+    } else {
+        Url::parse(&format!("http://{}", url))
+    }
+        .with_context(|| format!("Invalid url: {}", url))?;
+    if base_url.query().is_some() || base_url.fragment().is_some() {
+        bail!(
+            "Base url must not contain query or fragment parts: {}", &base_url
+        );
+    }
+    if !base_url.path().ends_with('/') {
+        let mut base_url_path_segments = match base_url.path_segments_mut() {
+            Ok(segments) => segments,
+            Err(()) => bail!("Not a base url"),
+        };
+        base_url_path_segments.push("");
+    }
+    base_url
+}
+
 pub async fn resolve_global_labels(
-    config: &PreparedConfig, client: &reqwest::Client, timeout: Duration,
+    config: &PreparedConfig, timeout: Duration,
 ) -> Result<BTreeMap<String, String>, AnyError> {
     let mut global_labels = BTreeMap::new();
     for global_label in config.global_labels.iter() {
         let text_resp = fetch_text_content(
-            &client, global_label.url.clone(), timeout
+            &config.client, global_label.url.clone(), global_label.auth.as_ref(), timeout
         ).await?;
         let labels_json = serde_json::from_str(&text_resp)?;
         let labels_root_match = Match {
@@ -176,96 +686,322 @@ pub async fn info() -> impl Responder {
             </head>
             <body>
               <p>To the <a href="/metrics">metrics page</a></p>
+              <p>Probe an allowed target: <code>/probe?target=http://localhost:9200&amp;module=name</code></p>
             </body>
           </html>
         "#)
 }
 
 pub async fn metrics(
-    state: web::Data<AppState>
-) -> Result<impl Responder, ProcessMetricsError> {
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let encoding = match negotiate_encoding(&req) {
+        Some(encoding) => encoding,
+        None => return not_acceptable(),
+    };
+    match &state.loaded().target {
+        Some(target) => target_metrics(
+            &state, target, negotiate_format(&req), encoding
+        ).await,
+        None => HttpResponse::NotFound()
+            .body("Base url is not configured, use /probe?target=<url>"),
+    }
+}
+
+pub async fn probe(
+    state: web::Data<AppState>,
+    params: web::Query<ProbeParams>,
+    req: HttpRequest,
+) -> Result<impl Responder, ProbeError> {
+    let encoding = match negotiate_encoding(&req) {
+        Some(encoding) => encoding,
+        None => return Ok(not_acceptable()),
+    };
+    let target = state.loaded().probe.target(
+        &state, params.module.as_deref(), &params.target
+    ).await?;
+    Ok(
+        target_metrics(
+            &state, &target, negotiate_format(&req), encoding
+        ).await
+    )
+}
+
+fn not_acceptable() -> HttpResponse {
+    HttpResponse::NotAcceptable()
+        .body("None of the accepted content codings is supported: zstd, gzip, deflate, identity")
+}
+
+/// Chooses the format with the highest quality from the `Accept` header,
+/// Prometheus text format is used by default
+fn negotiate_format(req: &HttpRequest) -> Format {
+    let accept = match req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok()) {
+        Some(accept) => accept,
+        None => return Format::Prometheus,
+    };
+    let mut best: Option<(Format, f32)> = None;
+    for media_range in accept.split(',') {
+        let mut params = media_range.split(';').map(str::trim);
+        let media_type = params.next().unwrap_or_default();
+        let format = if media_type.eq_ignore_ascii_case(OPENMETRICS_MEDIA_TYPE) {
+            Format::OpenMetrics
+        } else if media_type.eq_ignore_ascii_case("text/plain") ||
+            media_type == "text/*" ||
+            media_type == "*/*"
+        {
+            Format::Prometheus
+        } else {
+            continue;
+        };
+        let quality = parse_quality(params);
+        let is_better = match best {
+            Some((_, best_quality)) => quality > best_quality,
+            None => quality > 0.0,
+        };
+        if is_better {
+            best = Some((format, quality));
+        }
+    }
+    best.map_or(Format::Prometheus, |(format, _)| format)
+}
+
+/// Chooses the encoding with the highest quality from the `Accept-Encoding` header,
+/// metrics are not compressed when the header is missing. Identity is acceptable
+/// unless it is excluded with `identity;q=0` or `*;q=0`, then `None` is returned
+/// when no other encoding is acceptable
+fn negotiate_encoding(req: &HttpRequest) -> Option<Encoding> {
+    let accept = match req.headers().get(header::ACCEPT_ENCODING).and_then(|v| v.to_str().ok()) {
+        Some(accept) => accept,
+        None => return Some(Encoding::Identity),
+    };
+    let codings = accept.split(',')
+        .filter_map(|coding| {
+            let mut params = coding.split(';').map(str::trim);
+            match params.next() {
+                Some(name) if !name.is_empty() => Some((name, parse_quality(params))),
+                _ => None,
+            }
+        })
+        .collect::<Vec<_>>();
+    let mut best: Option<(Encoding, f32)> = None;
+    for &encoding in Encoding::ALL.iter() {
+        let quality = codings.iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding.name()))
+            .or_else(|| codings.iter().find(|(name, _)| *name == "*"))
+            .map(|&(_, quality)| quality);
+        let quality = match quality {
+            Some(quality) if quality > 0.0 => quality,
+            Some(_) => continue,
+            None if encoding == Encoding::Identity => f32::MIN_POSITIVE,
+            None => continue,
+        };
+        let is_better = match best {
+            Some((_, best_quality)) => quality > best_quality,
+            None => true,
+        };
+        if is_better {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+fn parse_quality<'a>(mut params: impl Iterator<Item = &'a str>) -> f32 {
+    params
+        .find_map(|param| param.strip_prefix("q="))
+        .and_then(|q| q.parse::<f32>().ok())
+        .unwrap_or(1.0)
+}
+
+async fn target_metrics(
+    state: &AppState, target: &TargetState, format: Format, encoding: Encoding
+) -> HttpResponse {
+    let cache = target.cache(format);
     {
-        let cached_metrics = state.cache.read().await;
+        let cached_metrics = cache.read().await;
         if cached_metrics.is_initialized() &&
             Instant::now() < cached_metrics.expired_at
         {
-            return Ok(cached_metrics.to_response());
+            state.self_metrics.observe_cache(&target.name, true);
+            return cached_metrics.to_response(encoding);
         }
     }
 
-    let mut cached_metrics = match state.cache.try_write() {
+    let mut cached_metrics = match cache.try_write() {
         Ok(cached_metrics) => {
             cached_metrics
         }
         Err(()) => {
-            let cached_metrics = state.cache.read().await;
-            return Ok(cached_metrics.to_response());
+            // Metrics are being updated right now so wait for them
+            let cached_metrics = cache.read().await;
+            state.self_metrics.observe_cache(&target.name, true);
+            return cached_metrics.to_response(encoding);
         }
     };
+    state.self_metrics.observe_cache(&target.name, false);
 
     let buf = &mut cached_metrics.buf;
     buf.clear();
     log::trace!("Initial buffer capacity: {}", buf.capacity());
 
-    match process_metrics(state, buf).await {
+    match process_metrics(state, target, format, buf).await {
         Ok(()) => cached_metrics.set_ok(),
         Err(e) => cached_metrics.set_error(e),
     };
 
-    Ok(cached_metrics.to_response())
+    cached_metrics.to_response(encoding)
+}
+
+pub async fn reload(
+    state: web::Data<AppState>
+) -> impl Responder {
+    match state.reload().await {
+        Ok(checksum) => HttpResponse::Ok()
+            .body(format!("Config was reloaded, checksum: {:08x}\n", checksum)),
+        Err(e) => HttpResponse::InternalServerError()
+            .body(format!("Error when reloading config: {:#}\n", e)),
+    }
 }
 
-fn prometheus_response(data: Vec<u8>) -> HttpResponse {
+pub async fn self_metrics(
+    state: web::Data<AppState>,
+    req: HttpRequest,
+) -> impl Responder {
+    let format = negotiate_format(&req);
+    let mut buf = vec!();
+    state.self_metrics.dump(format, &mut buf);
+    format.dump_eof(&mut buf);
     HttpResponse::Ok()
-        .content_type(PROMETHEUS_CONTENT_TYPE)
-        .header(header::CONTENT_ENCODING, ContentEncoding::Gzip.as_str())
-        .body(data)
+        .content_type(format.content_type())
+        .body(buf)
+}
+
+fn metrics_response(format: Format, encoding: Encoding, body: Bytes) -> HttpResponse {
+    let mut resp = HttpResponse::Ok();
+    resp.content_type(format.content_type())
+        .header(header::VARY, "Accept, Accept-Encoding");
+    if encoding != Encoding::Identity {
+        resp.header(header::CONTENT_ENCODING, encoding.name());
+    }
+    resp.body(body)
 }
 
 async fn process_metrics(
-    state: web::Data<AppState>, buf: &mut Vec<u8>
+    state: &AppState, target: &TargetState, format: Format, buf: &mut Vec<u8>
 ) -> Result<(), ProcessMetricsError> {
     let mut requests_duration = Duration::default();
     let mut json_parsing_duration = Duration::default();
     let mut processing_duration = Duration::default();
 
-    let semaphore = Arc::new(Semaphore::new(state.concurrency as usize));
-    let resp_futures = state.config.endpoints.iter()
+    let resp_futures = target.config.endpoints.iter()
         .map(|endpoint| {
             let endpoint_url = endpoint.url.clone();
-            let client = state.client.clone();
+            let endpoint_auth = endpoint.auth.as_ref();
+            let client = target.config.client.clone();
             let timeout = state.timeout;
-            let semaphore = semaphore.clone();
+            let semaphore = target.semaphore.clone();
             async move {
                 let _permit = semaphore.acquire().await;
                 let start_request = Instant::now();
-                let resp = fetch_text_content(&client, endpoint_url, timeout).await;
-                resp.map(|r| (r, start_request.elapsed()))
+                let resp = fetch_text_content(
+                    &client, endpoint_url, endpoint_auth, timeout
+                ).await;
+                (resp, start_request.elapsed())
             }
         })
         .collect::<Vec<_>>();
 
-    let responses = try_join_all(resp_futures).await?;
+    let responses = join_all(resp_futures).await;
 
-    let mut writer = GzEncoder::new(buf, Compression::default());
-    for (endpoint, (text_resp, request_duration)) in
-        state.config.endpoints.iter().zip(responses.iter())
+    let mut families = MetricFamilies::new(
+        format, target.config.duplicate_series, target.config.invalid_names
+    )
+        .with_type_overrides(&target.config.metric_type_overrides);
+    let mut endpoints_status = vec!();
+    let mut endpoints_stats = vec!();
+    for (endpoint, (resp, request_duration)) in
+        target.config.endpoints.iter().zip(responses)
     {
-        requests_duration += *request_duration;
+        requests_duration += request_duration;
+
+        let text_resp = match resp {
+            Ok(text_resp) => text_resp,
+            Err(e) => {
+                log::error!("Error when fetching {}: {}", &endpoint.url, e);
+                endpoints_status.push(EndpointStatus {
+                    endpoint,
+                    up: false,
+                    scrape_duration: request_duration,
+                });
+                endpoints_stats.push(EndpointScrapeStats {
+                    endpoint: endpoint.label_value().to_string(),
+                    request_duration,
+                    parsing_duration: Duration::default(),
+                    processing_duration: Duration::default(),
+                    series: 0,
+                    warnings: vec!(),
+                });
+                continue;
+            }
+        };
 
         let start_parsing = Instant::now();
-        let json = serde_json::from_str(&text_resp)?;
-        json_parsing_duration += start_parsing.elapsed();
+        let json = match serde_json::from_str(&text_resp) {
+            Ok(json) => json,
+            Err(e) => {
+                let parsing_duration = start_parsing.elapsed();
+                log::error!("Invalid json from {}: {}", &endpoint.url, e);
+                endpoints_status.push(EndpointStatus {
+                    endpoint,
+                    up: false,
+                    scrape_duration: request_duration + parsing_duration,
+                });
+                endpoints_stats.push(EndpointScrapeStats {
+                    endpoint: endpoint.label_value().to_string(),
+                    request_duration,
+                    parsing_duration,
+                    processing_duration: Duration::default(),
+                    series: 0,
+                    warnings: vec!(),
+                });
+                continue;
+            }
+        };
+        let parsing_duration = start_parsing.elapsed();
+        json_parsing_duration += parsing_duration;
 
         let start_processing = Instant::now();
-        for (level, msg) in endpoint.process(
-            &state.root_metric, &json, &mut writer
-        ) {
-            log::log!(level, "{}", msg);
+        let process_stats = endpoint.process(&target.root_metric, &json, &mut families);
+        let endpoint_processing_duration = start_processing.elapsed();
+        processing_duration += endpoint_processing_duration;
+        for warning in &process_stats.warnings {
+            log::log!(warning.level, "{}", warning.message);
         }
-        processing_duration += start_processing.elapsed();
+
+        endpoints_status.push(EndpointStatus {
+            endpoint,
+            up: true,
+            scrape_duration: request_duration + parsing_duration + endpoint_processing_duration,
+        });
+        endpoints_stats.push(EndpointScrapeStats {
+            endpoint: endpoint.label_value().to_string(),
+            request_duration,
+            parsing_duration,
+            processing_duration: endpoint_processing_duration,
+            series: process_stats.series,
+            warnings: process_stats.warnings.iter()
+                .map(|w| w.kind)
+                .collect(),
+        });
+    }
+    for warning in add_endpoints_status(&target.root_metric, &endpoints_status, &mut families) {
+        log::log!(warning.level, "{}", warning.message);
     }
-    writer.finish()?;
+    families.dump(buf);
+    format.dump_eof(buf);
+
+    state.self_metrics.observe_scrape(&target.name, endpoints_stats);
 
     log::info!(
         "Timings: requests_total={}ms, parsing={}ms, processing={}ms",
@@ -278,18 +1014,150 @@ async fn process_metrics(
 }
 
 async fn fetch_text_content(
-    client: &reqwest::Client, url: Url, timeout: Duration
+    client: &reqwest::Client, url: Url, auth: Option<&PreparedAuth>, timeout: Duration
 ) -> Result<String, ProcessMetricsError> {
 
-    async fn fetch(client: &reqwest::Client, url: Url) -> Result<String, reqwest::Error> {
+    async fn fetch(
+        client: &reqwest::Client, url: Url, auth: Option<&PreparedAuth>
+    ) -> Result<String, reqwest::Error> {
         log::debug!("Fetching url: {}", &url);
-        client.get(url).send().await?
+        let mut req = client.get(url);
+        if let Some(auth) = auth {
+            if let Some((username, password)) = &auth.basic {
+                req = req.basic_auth(username, password.as_ref());
+            }
+            if let Some(token) = &auth.bearer {
+                req = req.bearer_auth(token);
+            }
+            for (name, value) in &auth.headers {
+                req = req.header(name, value);
+            }
+        }
+        // Error bodies must not be processed as metrics,
+        // so the endpoint is reported as down on any non-success status
+        req.send().await?
+            .error_for_status()?
             .text().await
     }
 
     Ok(
         timeout_at(tokio::time::Instant::now() + timeout, async move {
-            fetch(client, url).await
+            fetch(client, url, auth).await
         }).await??
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header;
+    use actix_web::test::TestRequest;
+
+    use indoc::indoc;
+
+    use std::collections::HashMap;
+
+    use super::{negotiate_encoding, negotiate_format, parse_base_url, Encoding, ProbeTargets};
+    use crate::config::Config;
+    use crate::convert::Format;
+
+    #[test]
+    fn test_parse_base_url() {
+        let base_url = |url: &str| parse_base_url(url).map(|url| url.to_string());
+        assert_eq!(base_url("http://es:9200").unwrap(), "http://es:9200/");
+        assert_eq!(base_url("http://es:9200/").unwrap(), "http://es:9200/");
+        assert_eq!(base_url("es:9200").unwrap(), "http://es:9200/");
+        assert_eq!(base_url("https://es/cluster").unwrap(), "https://es/cluster/");
+        assert_eq!(base_url("https://es/cluster/").unwrap(), "https://es/cluster/");
+        assert!(base_url("http://es:9200/?pretty").is_err());
+        assert!(base_url("http://es:9200/#nodes").is_err());
+        assert!(base_url("http://es:port").is_err());
+    }
+
+    #[test]
+    fn test_negotiate_format() {
+        let format = |accept: &str| {
+            negotiate_format(&TestRequest::with_header(header::ACCEPT, accept).to_http_request())
+        };
+        assert_eq!(negotiate_format(&TestRequest::default().to_http_request()), Format::Prometheus);
+        assert_eq!(format("*/*"), Format::Prometheus);
+        assert_eq!(
+            format("application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"),
+            Format::OpenMetrics
+        );
+        assert_eq!(
+            format("application/openmetrics-text;q=0.5,text/plain"),
+            Format::Prometheus
+        );
+        assert_eq!(format("application/json"), Format::Prometheus);
+    }
+
+    #[test]
+    fn test_negotiate_encoding() {
+        let encoding = |accept: &str| {
+            negotiate_encoding(
+                &TestRequest::with_header(header::ACCEPT_ENCODING, accept).to_http_request()
+            )
+        };
+        assert_eq!(
+            negotiate_encoding(&TestRequest::default().to_http_request()),
+            Some(Encoding::Identity)
+        );
+        assert_eq!(encoding("gzip"), Some(Encoding::Gzip));
+        assert_eq!(encoding("gzip, deflate, br"), Some(Encoding::Gzip));
+        assert_eq!(encoding("deflate;q=0.5, gzip;q=0.2"), Some(Encoding::Deflate));
+        assert_eq!(encoding("gzip;q=0.5, zstd"), Some(Encoding::Zstd));
+        assert_eq!(encoding("*"), Some(Encoding::Zstd));
+        assert_eq!(encoding("*, zstd;q=0"), Some(Encoding::Gzip));
+        assert_eq!(encoding("identity, gzip;q=0.5"), Some(Encoding::Identity));
+        assert_eq!(encoding("br"), Some(Encoding::Identity));
+        assert_eq!(encoding(""), Some(Encoding::Identity));
+        assert_eq!(encoding("identity;q=0, gzip;q=0.1"), Some(Encoding::Gzip));
+        assert_eq!(encoding("*;q=0, identity"), Some(Encoding::Identity));
+        assert_eq!(encoding("identity;q=0"), None);
+        assert_eq!(encoding("br, identity;q=0"), None);
+        assert_eq!(encoding("*;q=0"), None);
+    }
+
+    #[test]
+    fn test_probe_target_allowed() {
+        let config = |yaml: &str| serde_yaml::from_str::<Config>(yaml).unwrap();
+        let without_credentials = config(indoc! {"
+            global_labels: []
+            endpoints: []
+        "});
+        let with_credentials = config(indoc! {"
+            auth:
+              basic:
+                username: monitoring
+            global_labels: []
+            endpoints: []
+        "});
+        let es_1 = parse_base_url("http://es-1:9200").unwrap();
+        let es_2 = parse_base_url("http://es-2:9200").unwrap();
+        let probe_targets = |allowed_targets: &[&url::Url], allow_any_target| {
+            ProbeTargets::new(
+                None,
+                HashMap::new(),
+                allowed_targets.iter().map(|&url| url.clone()).collect(),
+                allow_any_target,
+                config("{global_labels: [], endpoints: []}"),
+                HashMap::new(),
+            )
+        };
+
+        let probe = probe_targets(&[], false);
+        assert!(!probe.is_allowed(&without_credentials, &es_1));
+        assert!(!probe.is_allowed(&with_credentials, &es_1));
+
+        let probe = probe_targets(&[], true);
+        assert!(probe.is_allowed(&without_credentials, &es_1));
+        assert!(!probe.is_allowed(&with_credentials, &es_1));
+
+        for &allow_any_target in &[false, true] {
+            let probe = probe_targets(&[&es_1], allow_any_target);
+            assert!(probe.is_allowed(&without_credentials, &es_1));
+            assert!(probe.is_allowed(&with_credentials, &es_1));
+            assert!(!probe.is_allowed(&without_credentials, &es_2));
+        }
+    }
+}
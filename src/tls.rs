@@ -0,0 +1,251 @@
+use anyhow::{anyhow, bail, Context, Error as AnyhowError};
+
+use fehler::throws;
+
+use openssl::pkcs12::Pkcs12;
+use openssl::pkey::{PKey, Private};
+use openssl::stack::Stack;
+use openssl::x509::X509;
+
+use rustls::{
+    Certificate,
+    ClientConfig,
+    NoClientAuth,
+    PrivateKey,
+    RootCertStore,
+    ServerCertVerified,
+    ServerCertVerifier,
+    ServerConfig,
+    TLSError,
+    WebPKIVerifier,
+};
+use rustls::internal::pemfile::pkcs8_private_keys;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use webpki::{DNSName, DNSNameRef};
+
+use crate::config::Tls;
+
+struct ClientIdentity {
+    cert: X509,
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+
+/// Creates http client for the TLS settings. Native TLS is used by default but it
+/// is not able to verify a certificate against other name than the url host,
+/// so rustls is used when `server_name` is set
+#[throws(AnyhowError)]
+pub(crate) fn create_client(tls: Option<&Tls>) -> reqwest::Client {
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return reqwest::Client::new(),
+    };
+    let ca_certs = match &tls.ca_file {
+        Some(ca_file) => read_certs(ca_file)?,
+        None => vec!(),
+    };
+    let identity = read_identity(tls)?;
+
+    let builder = match &tls.server_name {
+        Some(server_name) if !tls.insecure_skip_verify => {
+            reqwest::Client::builder()
+                .use_preconfigured_tls(
+                    rustls_config(&ca_certs, identity.as_ref(), server_name)?
+                )
+        }
+        _ => {
+            let mut builder = reqwest::Client::builder()
+                .use_native_tls()
+                .danger_accept_invalid_certs(tls.insecure_skip_verify);
+            for cert in &ca_certs {
+                builder = builder.add_root_certificate(
+                    reqwest::Certificate::from_der(&cert.to_der()?)?
+                );
+            }
+            if let Some(identity) = &identity {
+                builder = builder.identity(native_identity(identity)?);
+            }
+            builder
+        }
+    };
+    builder.build()?
+}
+
+#[throws(AnyhowError)]
+fn read_certs(path: &Path) -> Vec<X509> {
+    let certs = std::fs::read(path)
+        .with_context(|| format!("Cannot read certificates: {}", path.display()))?;
+    let certs = X509::stack_from_pem(&certs)
+        .with_context(|| format!("Invalid certificates: {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("No certificates found: {}", path.display());
+    }
+    certs
+}
+
+#[throws(AnyhowError)]
+fn read_private_key(path: &Path) -> PKey<Private> {
+    let key = std::fs::read(path)
+        .with_context(|| format!("Cannot read private key: {}", path.display()))?;
+    PKey::private_key_from_pem(&key)
+        .with_context(|| format!("Invalid private key: {}", path.display()))?
+}
+
+#[throws(AnyhowError)]
+fn read_identity(tls: &Tls) -> Option<ClientIdentity> {
+    match (&tls.cert_file, &tls.key_file, &tls.pkcs12_file) {
+        (None, None, None) => None,
+        (Some(cert_file), Some(key_file), None) => {
+            let mut certs = read_certs(cert_file)?;
+            let cert = certs.remove(0);
+            let key = read_private_key(key_file)?;
+            Some(ClientIdentity { cert, chain: certs, key })
+        }
+        (None, None, Some(pkcs12_file)) => {
+            let pkcs12 = std::fs::read(pkcs12_file)
+                .with_context(|| format!("Cannot read PKCS#12: {}", pkcs12_file.display()))?;
+            let password = tls.pkcs12_password.as_ref()
+                .map(|password| password.resolve())
+                .transpose()?
+                .unwrap_or_default();
+            let parsed = Pkcs12::from_der(&pkcs12)
+                .and_then(|pkcs12| pkcs12.parse(&password))
+                .with_context(|| format!("Invalid PKCS#12: {}", pkcs12_file.display()))?;
+            Some(ClientIdentity {
+                cert: parsed.cert,
+                chain: parsed.chain.map(|chain| chain.into_iter().collect()).unwrap_or_default(),
+                key: parsed.pkey,
+            })
+        }
+        (Some(_), None, None) => bail!("Client certificate requires a private key"),
+        (None, Some(_), None) => bail!("Private key requires a client certificate"),
+        (_, _, Some(_)) => bail!("Client certificate must be either in PEM or PKCS#12 format"),
+    }
+}
+
+#[throws(AnyhowError)]
+fn native_identity(identity: &ClientIdentity) -> reqwest::Identity {
+    let mut chain = Stack::new()?;
+    for cert in &identity.chain {
+        chain.push(cert.clone())?;
+    }
+    let mut pkcs12_builder = Pkcs12::builder();
+    pkcs12_builder.ca(chain);
+    let pkcs12 = pkcs12_builder.build("", "", &identity.key, &identity.cert)?;
+    reqwest::Identity::from_pkcs12_der(&pkcs12.to_der()?, "")?
+}
+
+#[throws(AnyhowError)]
+fn rustls_config(
+    ca_certs: &[X509],
+    identity: Option<&ClientIdentity>,
+    server_name: &str,
+) -> ClientConfig {
+    let server_name = DNSNameRef::try_from_ascii_str(server_name)
+        .map_err(|_| anyhow!("Invalid server name: {}", server_name))?
+        .to_owned();
+
+    let mut config = ClientConfig::new();
+    if ca_certs.is_empty() {
+        config.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    }
+    for cert in ca_certs {
+        config.root_store.add(&Certificate(cert.to_der()?))
+            .map_err(|e| anyhow!("Invalid CA certificate: {:?}", e))?;
+    }
+    if let Some(identity) = identity {
+        let mut cert_chain = vec!(Certificate(identity.cert.to_der()?));
+        for cert in &identity.chain {
+            cert_chain.push(Certificate(cert.to_der()?));
+        }
+        config.set_single_client_cert(cert_chain, rustls_private_key(&identity.key)?)?;
+    }
+    config.dangerous().set_certificate_verifier(Arc::new(ServerNameVerifier {
+        server_name,
+        verifier: WebPKIVerifier::new(),
+    }));
+    config
+}
+
+/// Creates config to serve the exporter over https
+#[throws(AnyhowError)]
+pub(crate) fn server_config(cert_file: &Path, key_file: &Path) -> ServerConfig {
+    let mut cert_chain = vec!();
+    for cert in read_certs(cert_file)? {
+        cert_chain.push(Certificate(cert.to_der()?));
+    }
+    let key = rustls_private_key(&read_private_key(key_file)?)?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(cert_chain, key)?;
+    config
+}
+
+/// Rustls does not understand some of the traditional key formats so always pass PKCS#8
+#[throws(AnyhowError)]
+fn rustls_private_key(key: &PKey<Private>) -> PrivateKey {
+    pkcs8_private_keys(&mut key.private_key_to_pem_pkcs8()?.as_slice())
+        .ok()
+        .and_then(|mut keys| keys.pop())
+        .ok_or_else(|| anyhow!("Cannot convert private key"))?
+}
+
+/// Verifies server certificate against the configured name ignoring the url host
+struct ServerNameVerifier {
+    server_name: DNSName,
+    verifier: WebPKIVerifier,
+}
+
+impl ServerCertVerifier for ServerNameVerifier {
+    fn verify_server_cert(
+        &self,
+        roots: &RootCertStore,
+        presented_certs: &[Certificate],
+        _dns_name: DNSNameRef,
+        ocsp_response: &[u8],
+    ) -> Result<ServerCertVerified, TLSError> {
+        self.verifier.verify_server_cert(
+            roots, presented_certs, self.server_name.as_ref(), ocsp_response
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::create_client;
+    use crate::config::Tls;
+
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_create_client() {
+        assert!(create_client(None).is_ok());
+        assert!(create_client(Some(&Tls {
+            server_name: Some("es.example.com".to_string()),
+            ..Default::default()
+        })).is_ok());
+        assert_eq!(
+            create_client(Some(&Tls {
+                server_name: Some("not a hostname".to_string()),
+                ..Default::default()
+            })).err().unwrap().to_string(),
+            "Invalid server name: not a hostname"
+        );
+        assert_eq!(
+            create_client(Some(&Tls {
+                cert_file: Some(PathBuf::from("client.pem")),
+                ..Default::default()
+            })).err().unwrap().to_string(),
+            "Client certificate requires a private key"
+        );
+        assert_eq!(
+            create_client(Some(&Tls {
+                ca_file: Some(PathBuf::from("Cargo.toml")),
+                ..Default::default()
+            })).err().unwrap().to_string(),
+            "No certificates found: Cargo.toml"
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use actix_web::http::header::{self, HeaderMap};
+
+use anyhow::{Context, Error as AnyhowError};
+
+use fehler::throws;
+
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+
+use serde::Deserialize;
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::tls;
+
+/// Verified credentials are forgotten when there are more of them
+const MAX_VERIFIED_CREDENTIALS: usize = 100;
+/// Bcrypt verifications of the credentials that are not verified yet
+/// allowed per second, the rest of the requests are rejected
+const MAX_VERIFICATIONS_PER_SEC: u32 = 10;
+
+/// Web config in the format of Prometheus exporter toolkit, the keys
+/// that are not supported are rejected
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct WebConfig {
+    pub tls_server_config: Option<TlsServerConfig>,
+    /// Bcrypt hashes of the user passwords
+    #[serde(default)]
+    pub basic_auth_users: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsServerConfig {
+    pub cert_file: PathBuf,
+    pub key_file: PathBuf,
+}
+
+impl WebConfig {
+    #[throws(AnyhowError)]
+    pub fn read(path: &Path) -> Self {
+        let file = File::open(path)
+            .with_context(|| format!("Cannot read web config: {}", path.display()))?;
+        serde_yaml::from_reader(BufReader::new(file))
+            .with_context(|| format!("Invalid web config: {}", path.display()))?
+    }
+
+    /// Returns `None` when the exporter should be served over plain http
+    #[throws(AnyhowError)]
+    pub fn rustls_config(&self) -> Option<rustls::ServerConfig> {
+        match &self.tls_server_config {
+            Some(tls_config) => Some(
+                tls::server_config(&tls_config.cert_file, &tls_config.key_file)?
+            ),
+            None => None,
+        }
+    }
+
+    #[throws(AnyhowError)]
+    pub fn basic_auth(&self) -> BasicAuthUsers {
+        let max_cost = self.basic_auth_users.values()
+            .filter_map(|password_hash| password_hash.split('$').nth(2)?.parse().ok())
+            .max();
+        let dummy_hash = match max_cost {
+            Some(cost) => bcrypt::hash("", cost).context("Invalid bcrypt cost")?,
+            None => String::new(),
+        };
+        let mut digest_key = vec!(0; 32);
+        openssl::rand::rand_bytes(&mut digest_key)?;
+        BasicAuthUsers {
+            users: Arc::new(self.basic_auth_users.clone()),
+            dummy_hash: Arc::new(dummy_hash),
+            digest_key: Arc::new(digest_key),
+            verified: Arc::new(Mutex::new(HashSet::new())),
+            verifications: Arc::new(Mutex::new((Instant::now(), 0))),
+        }
+    }
+}
+
+/// Checks credentials of the incoming requests, all requests are allowed
+/// when there are no users
+#[derive(Clone)]
+pub struct BasicAuthUsers {
+    users: Arc<HashMap<String, String>>,
+    /// Verified for unknown users so they take as long as known ones
+    dummy_hash: Arc<String>,
+    /// Random key of the credential digests
+    digest_key: Arc<Vec<u8>>,
+    /// Bcrypt is slow and blocks the server, so digests of successfully
+    /// verified credentials are remembered
+    verified: Arc<Mutex<HashSet<Vec<u8>>>>,
+    /// Start of the current second and the number of verifications within it
+    verifications: Arc<Mutex<(Instant, u32)>>,
+}
+
+impl BasicAuthUsers {
+    pub fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        if self.users.is_empty() {
+            return true;
+        }
+        let credentials = headers.get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|value| base64::decode(value.trim()).ok())
+            .and_then(|value| String::from_utf8(value).ok());
+        let credentials = match &credentials {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        let (username, password) = match credentials.find(':') {
+            Some(ix) => (&credentials[..ix], &credentials[ix + 1..]),
+            None => return false,
+        };
+        let digest = self.credentials_digest(username, password).ok();
+        if let Some(digest) = &digest {
+            if self.verified.lock().expect("verified credentials lock").contains(digest) {
+                return true;
+            }
+        }
+        if !self.acquire_verification() {
+            log::warn!("Too many failed authorizations, rejecting request");
+            return false;
+        }
+        let (password_hash, known_user) = match self.users.get(username) {
+            Some(password_hash) => (password_hash, true),
+            None => (&*self.dummy_hash, false),
+        };
+        if !bcrypt::verify(password, password_hash).unwrap_or(false) || !known_user {
+            return false;
+        }
+        if let Some(digest) = digest {
+            let mut verified = self.verified.lock().expect("verified credentials lock");
+            if verified.len() >= MAX_VERIFIED_CREDENTIALS {
+                verified.clear();
+            }
+            verified.insert(digest);
+        }
+        true
+    }
+
+    /// HMAC of the credentials so the passwords are not kept in memory
+    fn credentials_digest(&self, username: &str, password: &str) -> Result<Vec<u8>, ErrorStack> {
+        let key = PKey::hmac(&self.digest_key)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+        signer.update(&(username.len() as u64).to_be_bytes())?;
+        signer.update(username.as_bytes())?;
+        signer.update(password.as_bytes())?;
+        signer.sign_to_vec()
+    }
+
+    fn acquire_verification(&self) -> bool {
+        let mut verifications = self.verifications.lock().expect("verifications lock");
+        let now = Instant::now();
+        if now.duration_since(verifications.0) >= Duration::from_secs(1) {
+            *verifications = (now, 0);
+        }
+        if verifications.1 >= MAX_VERIFICATIONS_PER_SEC {
+            return false;
+        }
+        verifications.1 += 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{self, HeaderMap, HeaderValue};
+
+    use indoc::indoc;
+
+    use super::{WebConfig, MAX_VERIFICATIONS_PER_SEC};
+
+    fn basic_auth_headers(credentials: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Basic {}", base64::encode(credentials))).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_basic_auth() {
+        let web_config: WebConfig = serde_yaml::from_str(indoc! {"
+            basic_auth_users:
+              prometheus: $2y$04$9VAOy.g0rlPClyKyFU.A3uHP7WqIbV6dJGvYnuEpteZncSoDoJGi2
+        "}).unwrap();
+        let users = web_config.basic_auth().unwrap();
+        assert!(users.is_authorized(&basic_auth_headers("prometheus:secret")));
+        // verified credentials are taken from the cache
+        assert!(users.is_authorized(&basic_auth_headers("prometheus:secret")));
+        assert!(!users.is_authorized(&basic_auth_headers("prometheus:wrong")));
+        assert!(!users.is_authorized(&basic_auth_headers("prometheus:wrong")));
+        assert!(!users.is_authorized(&basic_auth_headers("grafana:secret")));
+        assert!(!users.is_authorized(&basic_auth_headers("prometheus")));
+        assert!(!users.is_authorized(&HeaderMap::new()));
+
+        assert!(WebConfig::default().basic_auth().unwrap().is_authorized(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn test_basic_auth_throttling() {
+        let web_config: WebConfig = serde_yaml::from_str(indoc! {"
+            basic_auth_users:
+              prometheus: $2y$04$9VAOy.g0rlPClyKyFU.A3uHP7WqIbV6dJGvYnuEpteZncSoDoJGi2
+        "}).unwrap();
+        let users = web_config.basic_auth().unwrap();
+        for _ in 0..MAX_VERIFICATIONS_PER_SEC {
+            assert!(!users.is_authorized(&basic_auth_headers("prometheus:wrong")));
+        }
+        assert!(!users.is_authorized(&basic_auth_headers("prometheus:secret")));
+
+        let users = web_config.basic_auth().unwrap();
+        assert!(users.is_authorized(&basic_auth_headers("prometheus:secret")));
+        for _ in 0..MAX_VERIFICATIONS_PER_SEC {
+            assert!(!users.is_authorized(&basic_auth_headers("grafana:wrong")));
+        }
+        // verified credentials are not throttled
+        assert!(users.is_authorized(&basic_auth_headers("prometheus:secret")));
+    }
+
+    #[test]
+    fn test_unsupported_web_config() {
+        assert!(serde_yaml::from_str::<WebConfig>(indoc! {"
+            tls_server_config:
+              cert_file: exporter.pem
+              key_file: exporter.key
+              client_auth_type: RequireAndVerifyClientCert
+        "}).is_err());
+        assert!(serde_yaml::from_str::<WebConfig>(indoc! {"
+            http_server_config:
+              http2: false
+        "}).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use jsonpath::{Match, Step};
 
 use json_exporter::config::Config;
-use json_exporter::convert::ResolvedMetric;
+use json_exporter::convert::{MetricFamilies, ResolvedMetric};
 use json_exporter::prepare::PreparedConfig;
 
 use std::fs::File;
@@ -57,16 +57,19 @@ fn test_elasticsearch() {
 
     let mut buf = vec!();
     for endpoint in &prepared_config.endpoints {
+        let mut families = MetricFamilies::default()
+            .with_type_overrides(&prepared_config.metric_type_overrides);
         match endpoint.url.as_str() {
             "http://example.com:9200/_cluster/health?" => {
                 let es_cluster_health = serde_json::from_str(ES_CLUSTER_HEALTH)
                     .expect("es cluster health");
                 assert_eq!(
                     endpoint.process(
-                        &root_metric, &es_cluster_health, &mut buf
-                    ),
+                        &root_metric, &es_cluster_health, &mut families
+                    ).warnings,
                     vec!()
                 );
+                families.dump(&mut buf);
                 buf.write_all(b"\n\n").unwrap();
             }
             "http://example.com:9200/_nodes/_local/stats?groups=_all" => {
@@ -74,10 +77,11 @@ fn test_elasticsearch() {
                     .expect("es nodes stats");
                 assert_eq!(
                     endpoint.process(
-                        &root_metric, &es_nodes_stats, &mut buf
-                    ),
+                        &root_metric, &es_nodes_stats, &mut families
+                    ).warnings,
                     vec!()
                 );
+                families.dump(&mut buf);
                 buf.write_all(b"\n\n").unwrap();
             }
             "http://example.com:9200/_all/_stats?groups=_all" => {
@@ -85,10 +89,11 @@ fn test_elasticsearch() {
                     .expect("es indices stats");
                 assert_eq!(
                     endpoint.process(
-                        &root_metric, &es_indices_stats, &mut buf
-                    ),
+                        &root_metric, &es_indices_stats, &mut families
+                    ).warnings,
                     vec!()
                 );
+                families.dump(&mut buf);
                 buf.write_all(b"\n\n").unwrap();
             }
             endpoint_url => {